@@ -0,0 +1,227 @@
+// audio.rs - loudness and onsets from PCM audio, one value per video frame
+//
+// Only uncompressed audio (WAVE_FORMAT_PCM, 8 or 16 bit) can be measured, that's what
+// `ffmpeg -c:a pcm_s16le` gives. Audio chunks are placed on the timeline by sample
+// count, so it doesn't matter how the muxer interleaved them.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use memmap2::Mmap;
+
+use super::avi::{self, AviLayout, StreamFormat};
+use super::{Frame, FrameType};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+
+/// Which audio measurement drives a parameter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    /// RMS level of the frame's audio
+    Loudness,
+    /// how much louder the frame got than the one before, peaks on hits
+    Onset,
+}
+
+impl std::str::FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Signal, String> {
+        match s.trim() {
+            "loud" | "loudness" => Ok(Signal::Loudness),
+            "onset" | "onsets" => Ok(Signal::Onset),
+            other => Err(format!("unknown audio signal '{}', use loud or onset", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signal::Loudness => write!(f, "loud"),
+            Signal::Onset => write!(f, "onset"),
+        }
+    }
+}
+
+/// Audio levels, normalized to 0..1 over the clip
+#[derive(Clone, Debug, Default)]
+pub struct AudioEnvelope {
+    pub loudness: Vec<f32>,
+    pub onset: Vec<f32>,
+}
+
+impl AudioEnvelope {
+    pub fn get(&self, signal: Signal, i: usize) -> f32 {
+        let values = match signal {
+            Signal::Loudness => &self.loudness,
+            Signal::Onset => &self.onset,
+        };
+        values.get(i).copied().unwrap_or(0.0)
+    }
+
+    /// Re-index from video frame numbers to frame table positions (`Frame::index`).
+    /// Audio chunks in the table get the level of the video frame before them.
+    pub fn for_frame_table(&self, frame_table: &[Frame]) -> AudioEnvelope {
+        let mut video = 0usize;
+        let mut numbers = Vec::with_capacity(frame_table.len());
+        for frame in frame_table {
+            numbers.push(video.saturating_sub((frame.frame_type != FrameType::Video) as usize));
+            if frame.frame_type == FrameType::Video {
+                video += 1;
+            }
+        }
+        // the table is in source order, so its position is the frame's index
+        AudioEnvelope {
+            loudness: numbers.iter().map(|&n| self.get(Signal::Loudness, n)).collect(),
+            onset: numbers.iter().map(|&n| self.get(Signal::Onset, n)).collect(),
+        }
+    }
+}
+
+fn normalize(values: &mut [f32]) {
+    let max = values.iter().cloned().fold(0.0, f32::max);
+    if max > 0.0 {
+        values.iter_mut().for_each(|v| *v /= max);
+    }
+}
+
+/// Per video frame loudness and onset strength, or None without a PCM audio stream
+pub fn envelope(data: &[u8], layout: &AviLayout) -> Option<AudioEnvelope> {
+    let (stream_no, format) = layout.streams.iter().enumerate().find_map(|(i, s)| match &s.format {
+        StreamFormat::Audio(wf) if wf.format_tag == WAVE_FORMAT_PCM && matches!(wf.bits_per_sample, 8 | 16) => Some((i, wf)),
+        _ => None,
+    })?;
+    let audio_id = format!("{:02}wb", stream_no).into_bytes();
+    let bytes_per_sample = format.bits_per_sample as usize / 8;
+    let channels = format.channels.max(1) as usize;
+    let fps = layout.fps();
+    if format.samples_per_sec == 0 || fps <= 0.0 {
+        return None;
+    }
+    let samples_per_frame = format.samples_per_sec as f64 / fps;
+
+    let video_frames = layout.movi.children.iter()
+        .filter(|c| &c.id[2..] == b"dc" || &c.id[2..] == b"db")
+        .count();
+    let mut energy = vec![0.0f64; video_frames];
+    let mut counts = vec![0usize; video_frames];
+
+    let mut sample = 0usize;
+    for chunk in layout.movi.children.iter().filter(|c| c.id[..] == audio_id[..]) {
+        let payload = &data[chunk.data.clone()];
+        for block in payload.chunks_exact(bytes_per_sample * channels) {
+            let frame = (sample as f64 / samples_per_frame) as usize;
+            sample += 1;
+            if frame >= video_frames {
+                break;
+            }
+            for s in block.chunks_exact(bytes_per_sample) {
+                let v = match bytes_per_sample {
+                    1 => (s[0] as f64 - 128.0) / 128.0,
+                    _ => i16::from_le_bytes([s[0], s[1]]) as f64 / 32768.0,
+                };
+                energy[frame] += v * v;
+                counts[frame] += 1;
+            }
+        }
+    }
+    if counts.iter().all(|&c| c == 0) {
+        return None;
+    }
+
+    let mut loudness: Vec<f32> = energy.iter().zip(&counts)
+        .map(|(e, &c)| if c > 0 { (e / c as f64).sqrt() as f32 } else { 0.0 })
+        .collect();
+    normalize(&mut loudness);
+    let mut onset: Vec<f32> = (0..loudness.len())
+        .map(|i| if i == 0 { 0.0 } else { (loudness[i] - loudness[i - 1]).max(0.0) })
+        .collect();
+    normalize(&mut onset);
+
+    eprintln!("> Audio: {} Hz {}-bit {} channel PCM, {} frames measured", format.samples_per_sec, format.bits_per_sample, channels, video_frames);
+    Some(AudioEnvelope { loudness, onset })
+}
+
+pub fn read_envelope(path: &Path) -> io::Result<Option<AudioEnvelope>> {
+    let file = File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    let layout = avi::parse_avi(&mmap)?;
+    Ok(envelope(&mmap, &layout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::tests::{chunk, list};
+
+    fn header(fields: &[(usize, u32)], len: usize, fcc: &[u8; 4]) -> Vec<u8> {
+        let mut out = fcc.to_vec();
+        out.resize(len, 0);
+        for &(at, value) in fields {
+            out[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        out
+    }
+
+    /// 4 video frames at 10 fps and 100 Hz mono 16-bit PCM in two chunks,
+    /// each frame's 10 samples at a constant level
+    fn avi(levels: [i16; 4]) -> Vec<u8> {
+        let samples: Vec<u8> = levels.iter().flat_map(|&l| std::iter::repeat_n(l.to_le_bytes(), 10).flatten()).collect();
+        // format 1 (PCM) and 1 channel, 100 Hz, 200 bytes/s, block align 2 and 16 bits
+        let wave = header(&[(0, 1 | 1 << 16), (4, 100), (8, 200), (12, 2 | 16 << 16)], 16, &[0; 4]);
+        list(b"RIFF", b"AVI ", &[
+            list(b"LIST", b"hdrl", &[
+                chunk(b"avih", &[0; 56]),
+                list(b"LIST", b"strl", &[chunk(b"strh", &header(&[(20, 1), (24, 10)], 56, b"vids")), chunk(b"strf", &[0; 40])]),
+                list(b"LIST", b"strl", &[chunk(b"strh", &header(&[(20, 1), (24, 100)], 56, b"auds")), chunk(b"strf", &wave)]),
+            ]),
+            list(b"LIST", b"movi", &[
+                chunk(b"00dc", &[0; 4]),
+                chunk(b"01wb", &samples[..40]),
+                chunk(b"00dc", &[0; 4]),
+                chunk(b"00dc", &[0; 4]),
+                chunk(b"01wb", &samples[40..]),
+                chunk(b"00dc", &[0; 4]),
+            ]),
+        ])
+    }
+
+    fn close(a: &[f32], b: &[f32]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-4)
+    }
+
+    #[test]
+    fn loudness_and_onset_per_video_frame() {
+        let data = avi([0, 1000, 8000, 1000]);
+        let env = envelope(&data, &avi::parse_avi(&data).unwrap()).unwrap();
+        assert!(close(&env.loudness, &[0.0, 0.125, 1.0, 0.125]), "{:?}", env.loudness);
+        // rises of 0.125 and 0.875, normalized to the biggest
+        assert!(close(&env.onset, &[0.0, 0.125 / 0.875, 1.0, 0.0]), "{:?}", env.onset);
+        assert_eq!(env.get(Signal::Loudness, 2), 1.0);
+        assert_eq!(env.get(Signal::Onset, 99), 0.0);
+    }
+
+    #[test]
+    fn silence_and_no_audio() {
+        let data = avi([0; 4]);
+        let env = envelope(&data, &avi::parse_avi(&data).unwrap()).unwrap();
+        assert!(env.loudness.iter().chain(&env.onset).all(|&v| v == 0.0));
+
+        let video_only = crate::frames::tests::avi(&[(b"00dc", true, vec![0; 4])]);
+        assert!(envelope(&video_only, &avi::parse_avi(&video_only).unwrap()).is_none());
+    }
+
+    #[test]
+    fn frame_table_alignment() {
+        use FrameType::{Audio, Video};
+        let env = AudioEnvelope { loudness: vec![0.1, 0.2, 0.3], onset: vec![0.0, 1.0, 0.5] };
+        // audio before the first video frame, then after each video frame
+        let table: Vec<Frame> = [Audio, Video, Audio, Video, Video, Audio, Audio].into_iter().enumerate()
+            .map(|(i, frame_type)| Frame { index: i, offset: i, size: 1, rel_size: 0.0, frame_type, keyframe: false })
+            .collect();
+        let aligned = env.for_frame_table(&table);
+        assert_eq!(aligned.loudness, vec![0.1, 0.1, 0.1, 0.2, 0.3, 0.3, 0.3]);
+        assert_eq!(aligned.onset, vec![0.0, 0.0, 0.0, 1.0, 0.5, 0.5, 0.5]);
+    }
+}
@@ -0,0 +1,359 @@
+// avi.rs - RIFF/AVI chunk parsing
+
+use std::io;
+use std::ops::Range;
+
+pub const AVIIF_LIST: u32 = 0x01;
+pub const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// A single RIFF chunk. `data` is the byte range of the payload in the source
+/// buffer (after the 8 byte header, and after the list type for LIST/RIFF).
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub list_type: Option<[u8; 4]>,
+    pub offset: usize,
+    pub size: usize,
+    pub data: Range<usize>,
+    pub children: Vec<Chunk>,
+}
+
+impl Chunk {
+    pub fn is_list(&self) -> bool {
+        self.list_type.is_some()
+    }
+
+    /// First direct child with this id (or list type, for LISTs)
+    pub fn child(&self, id: &[u8; 4]) -> Option<&Chunk> {
+        self.children.iter().find(|c| &c.id == id || c.list_type.as_ref() == Some(id))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MainHeader {
+    pub micro_sec_per_frame: u32,
+    pub max_bytes_per_sec: u32,
+    pub padding_granularity: u32,
+    pub flags: u32,
+    pub total_frames: u32,
+    pub initial_frames: u32,
+    pub streams: u32,
+    pub suggested_buffer_size: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MainHeader {
+    pub fn fps(&self) -> f64 {
+        if self.micro_sec_per_frame == 0 { 0.0 } else { 1_000_000.0 / self.micro_sec_per_frame as f64 }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StreamHeader {
+    pub fcc_type: [u8; 4],
+    pub fcc_handler: [u8; 4],
+    pub flags: u32,
+    pub priority: u16,
+    pub language: u16,
+    pub initial_frames: u32,
+    pub scale: u32,
+    pub rate: u32,
+    pub start: u32,
+    pub length: u32,
+    pub suggested_buffer_size: u32,
+    pub quality: u32,
+    pub sample_size: u32,
+}
+
+impl StreamHeader {
+    pub fn fps(&self) -> f64 {
+        if self.scale == 0 { 0.0 } else { self.rate as f64 / self.scale as f64 }
+    }
+}
+
+/// BITMAPINFOHEADER from a video stream's strf
+#[derive(Clone, Debug, Default)]
+pub struct BitmapInfo {
+    pub width: i32,
+    pub height: i32,
+    pub planes: u16,
+    pub bit_count: u16,
+    pub compression: [u8; 4],
+    pub size_image: u32,
+}
+
+/// WAVEFORMATEX from an audio stream's strf
+#[derive(Clone, Debug, Default)]
+pub struct WaveFormat {
+    pub format_tag: u16,
+    pub channels: u16,
+    pub samples_per_sec: u32,
+    pub avg_bytes_per_sec: u32,
+    pub block_align: u16,
+    pub bits_per_sample: u16,
+}
+
+#[derive(Clone, Debug)]
+pub enum StreamFormat {
+    Video(BitmapInfo),
+    Audio(WaveFormat),
+    Other(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Stream {
+    pub header: StreamHeader,
+    pub format: StreamFormat,
+    /// byte offset of the strh payload in the source buffer, used to patch `length` on write
+    pub strh_offset: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    pub id: [u8; 4],
+    pub flags: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl IndexEntry {
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & AVIIF_KEYFRAME != 0
+    }
+}
+
+/// Everything tomatwo needs to know about the layout of an AVI file
+#[derive(Clone, Debug)]
+pub struct AviLayout {
+    pub riff: Chunk,
+    pub hdrl: Chunk,
+    pub main_header: MainHeader,
+    /// byte offset of the avih payload in the source buffer
+    pub avih_offset: usize,
+    pub streams: Vec<Stream>,
+    pub movi: Chunk,
+    pub idx1: Option<Chunk>,
+    pub index: Vec<IndexEntry>,
+}
+
+impl AviLayout {
+    pub fn video_stream(&self) -> Option<&Stream> {
+        self.streams.iter().find(|s| &s.header.fcc_type == b"vids")
+    }
+
+    pub fn audio_stream(&self) -> Option<&Stream> {
+        self.streams.iter().find(|s| &s.header.fcc_type == b"auds")
+    }
+
+    /// Frame rate of the video stream, falling back to avih
+    pub fn fps(&self) -> f64 {
+        match self.video_stream().map(|s| s.header.fps()) {
+            Some(fps) if fps > 0.0 => fps,
+            _ => self.main_header.fps(),
+        }
+    }
+}
+
+pub fn read_u16(data: &[u8], pos: usize) -> u16 {
+    data.get(pos..pos + 2).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> u32 {
+    data.get(pos..pos + 4).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub fn read_fourcc(data: &[u8], pos: usize) -> [u8; 4] {
+    let mut id = [0u8; 4];
+    if let Some(b) = data.get(pos..pos + 4) {
+        id.copy_from_slice(b);
+    }
+    id
+}
+
+pub fn fourcc_str(id: &[u8; 4]) -> String {
+    id.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Chunk ids that can show up inside movi, e.g. `00dc`, `01wb`, `ix00`, `JUNK`, `LIST`
+fn is_movi_chunk_id(id: &[u8; 4]) -> bool {
+    (id[0].is_ascii_digit() && id[1].is_ascii_digit() && id[2].is_ascii_alphabetic() && id[3].is_ascii_alphabetic())
+        || (&id[..2] == b"ix" && id[2].is_ascii_digit() && id[3].is_ascii_digit())
+        || id == b"JUNK"
+        || id == b"LIST"
+}
+
+fn parse_chunk(data: &[u8], offset: usize, end: usize) -> Option<Chunk> {
+    if offset + 8 > end {
+        return None;
+    }
+    let id = read_fourcc(data, offset);
+    let size = read_u32(data, offset + 4) as usize;
+    // our own (and plenty of other) broken files have wrong sizes, clamp to what's there
+    let data_end = (offset + 8).saturating_add(size).min(end);
+    let (list_type, data_start) = if &id == b"RIFF" || &id == b"LIST" {
+        (Some(read_fourcc(data, offset + 8)), (offset + 12).min(data_end))
+    } else {
+        (None, offset + 8)
+    };
+
+    let mut chunk = Chunk { id, list_type, offset, size, data: data_start..data_end, children: Vec::new() };
+
+    if let Some(list_type) = list_type {
+        if &list_type == b"movi" {
+            // the declared movi size is often wrong, trust the walk instead
+            let (children, walked_end) = parse_movi(data, data_start, end);
+            chunk.children = children;
+            chunk.data = data_start..walked_end.max(data_start);
+        } else {
+            let mut pos = data_start;
+            while let Some(child) = parse_chunk(data, pos, data_end) {
+                pos = if child.list_type.as_ref() == Some(b"movi") {
+                    child.data.end
+                } else {
+                    child.data.end + child.size % 2
+                };
+                chunk.children.push(child);
+            }
+        }
+    }
+
+    Some(chunk)
+}
+
+fn parse_movi(data: &[u8], start: usize, end: usize) -> (Vec<Chunk>, usize) {
+    let mut chunks = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let id = read_fourcc(data, pos);
+        if !is_movi_chunk_id(&id) {
+            break;
+        }
+        let size = read_u32(data, pos + 4) as usize;
+        let data_end = (pos + 8).saturating_add(size).min(end);
+        if &id == b"LIST" {
+            // 'rec ' lists group interleaved chunks, flatten them
+            chunks.extend(parse_movi(data, (pos + 12).min(data_end), data_end).0);
+        } else {
+            chunks.push(Chunk { id, list_type: None, offset: pos, size, data: pos + 8..data_end, children: Vec::new() });
+        }
+        pos = (data_end + (size & 1)).min(end);
+    }
+    (chunks, pos)
+}
+
+fn parse_main_header(data: &[u8], p: usize) -> MainHeader {
+    MainHeader {
+        micro_sec_per_frame: read_u32(data, p),
+        max_bytes_per_sec: read_u32(data, p + 4),
+        padding_granularity: read_u32(data, p + 8),
+        flags: read_u32(data, p + 12),
+        total_frames: read_u32(data, p + 16),
+        initial_frames: read_u32(data, p + 20),
+        streams: read_u32(data, p + 24),
+        suggested_buffer_size: read_u32(data, p + 28),
+        width: read_u32(data, p + 32),
+        height: read_u32(data, p + 36),
+    }
+}
+
+fn parse_stream_header(data: &[u8], p: usize) -> StreamHeader {
+    StreamHeader {
+        fcc_type: read_fourcc(data, p),
+        fcc_handler: read_fourcc(data, p + 4),
+        flags: read_u32(data, p + 8),
+        priority: read_u16(data, p + 12),
+        language: read_u16(data, p + 14),
+        initial_frames: read_u32(data, p + 16),
+        scale: read_u32(data, p + 20),
+        rate: read_u32(data, p + 24),
+        start: read_u32(data, p + 28),
+        length: read_u32(data, p + 32),
+        suggested_buffer_size: read_u32(data, p + 36),
+        quality: read_u32(data, p + 40),
+        sample_size: read_u32(data, p + 44),
+    }
+}
+
+fn parse_stream_format(data: &[u8], fcc_type: &[u8; 4], range: Range<usize>) -> StreamFormat {
+    let p = range.start;
+    match fcc_type {
+        b"vids" => StreamFormat::Video(BitmapInfo {
+            width: read_u32(data, p + 4) as i32,
+            height: read_u32(data, p + 8) as i32,
+            planes: read_u16(data, p + 12),
+            bit_count: read_u16(data, p + 14),
+            compression: read_fourcc(data, p + 16),
+            size_image: read_u32(data, p + 20),
+        }),
+        b"auds" => StreamFormat::Audio(WaveFormat {
+            format_tag: read_u16(data, p),
+            channels: read_u16(data, p + 2),
+            samples_per_sec: read_u32(data, p + 4),
+            avg_bytes_per_sec: read_u32(data, p + 8),
+            block_align: read_u16(data, p + 12),
+            bits_per_sample: read_u16(data, p + 14),
+        }),
+        _ => StreamFormat::Other(data[range].to_vec()),
+    }
+}
+
+/// Parse the RIFF tree of an AVI file held in memory
+pub fn parse_avi(data: &[u8]) -> io::Result<AviLayout> {
+    let riff = parse_chunk(data, 0, data.len()).ok_or_else(|| invalid("File too short to be an AVI"))?;
+    if &riff.id != b"RIFF" || riff.list_type.as_ref() != Some(b"AVI ") {
+        return Err(invalid("Not a RIFF AVI file"));
+    }
+
+    let hdrl = riff.child(b"hdrl").cloned().ok_or_else(|| invalid("Could not find 'hdrl' list in AVI file"))?;
+    let movi = riff.child(b"movi").cloned().ok_or_else(|| invalid("Could not find 'movi' list in AVI file"))?;
+    let avih = hdrl.child(b"avih").ok_or_else(|| invalid("Could not find 'avih' header in AVI file"))?;
+    let main_header = parse_main_header(data, avih.data.start);
+
+    let streams = hdrl.children.iter()
+        .filter(|c| c.list_type.as_ref() == Some(b"strl"))
+        .filter_map(|strl| {
+            let strh = strl.child(b"strh")?;
+            let header = parse_stream_header(data, strh.data.start);
+            let format = match strl.child(b"strf") {
+                Some(strf) => parse_stream_format(data, &header.fcc_type, strf.data.clone()),
+                None => StreamFormat::Other(Vec::new()),
+            };
+            Some(Stream { header, format, strh_offset: strh.data.start })
+        })
+        .collect();
+
+    let idx1 = riff.child(b"idx1").cloned();
+    let index = idx1.as_ref().map_or_else(Vec::new, |idx1| {
+        data[idx1.data.clone()].chunks_exact(16).map(|e| IndexEntry {
+            id: read_fourcc(e, 0),
+            flags: read_u32(e, 4),
+            offset: read_u32(e, 8),
+            size: read_u32(e, 12),
+        }).collect()
+    });
+
+    Ok(AviLayout { avih_offset: avih.data.start, riff, hdrl, main_header, streams, movi, idx1, index })
+}
+
+/// Patch avih and the video strh of a buffer laid out like `layout` (the whole
+/// file, or any prefix of it that covers hdrl) to play at `fps`
+pub fn set_frame_rate(data: &mut [u8], layout: &AviLayout, fps: f64) {
+    if fps <= 0.0 {
+        return;
+    }
+    let mut patch = |pos: usize, value: u32| {
+        if let Some(field) = data.get_mut(pos..pos + 4) {
+            field.copy_from_slice(&value.to_le_bytes());
+        }
+    };
+    patch(layout.avih_offset, (1_000_000.0 / fps).round() as u32);
+    for stream in layout.streams.iter().filter(|s| &s.header.fcc_type == b"vids") {
+        patch(stream.strh_offset + 20, 1000);
+        patch(stream.strh_offset + 24, (fps * 1000.0).round() as u32);
+    }
+}
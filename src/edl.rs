@@ -0,0 +1,226 @@
+// edl.rs - import/export of explicit frame order lists
+//
+// One row per output frame: source frame index, offset and size in movi, and type.
+// Text (whitespace separated), CSV and JSON are picked by file extension. On import
+// only the index is required, so a hand-written list of numbers works too.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use super::{ClipInfo, Frame, FrameType};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EdlEntry {
+    pub index: usize,
+    #[serde(default)]
+    pub offset: Option<usize>,
+    #[serde(default)]
+    pub size: Option<usize>,
+    #[serde(default, rename = "type")]
+    pub frame_type: Option<FrameType>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdlFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+impl EdlFormat {
+    pub fn from_path(path: &Path) -> EdlFormat {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("csv") => EdlFormat::Csv,
+            Some("json") => EdlFormat::Json,
+            _ => EdlFormat::Text,
+        }
+    }
+}
+
+fn type_name(frame_type: &FrameType) -> &'static str {
+    match frame_type {
+        FrameType::Video => "video",
+        FrameType::Audio => "audio",
+        FrameType::Void => "void",
+    }
+}
+
+fn parse_type(s: &str) -> Option<FrameType> {
+    match s.to_ascii_lowercase().as_str() {
+        "video" | "00dc" => Some(FrameType::Video),
+        "audio" | "01wb" => Some(FrameType::Audio),
+        "void" => Some(FrameType::Void),
+        _ => None,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn entries_from_frames(frames: &[Frame]) -> Vec<EdlEntry> {
+    frames.iter().map(|f| EdlEntry {
+        index: f.index,
+        offset: Some(f.offset),
+        size: Some(f.size),
+        frame_type: Some(f.frame_type.clone()),
+    }).collect()
+}
+
+pub fn format_edl(frames: &[Frame], format: EdlFormat) -> String {
+    match format {
+        EdlFormat::Json => serde_json::to_string_pretty(&entries_from_frames(frames)).unwrap_or_default(),
+        EdlFormat::Csv => {
+            let mut out = String::from("index,offset,size,type\n");
+            for f in frames {
+                out += &format!("{},{},{},{}\n", f.index, f.offset, f.size, type_name(&f.frame_type));
+            }
+            out
+        }
+        EdlFormat::Text => {
+            let mut out = String::from("# tomatwo edl: index offset size type\n");
+            for f in frames {
+                out += &format!("{} {} {} {}\n", f.index, f.offset, f.size, type_name(&f.frame_type));
+            }
+            out
+        }
+    }
+}
+
+pub fn write_edl(path: &Path, frames: &[Frame]) -> io::Result<()> {
+    fs::write(path, format_edl(frames, EdlFormat::from_path(path)))
+}
+
+pub fn parse_edl(text: &str, format: EdlFormat) -> io::Result<Vec<EdlEntry>> {
+    if format == EdlFormat::Json {
+        return serde_json::from_str(text).map_err(|e| invalid(format!("Bad EDL json: {}", e)));
+    }
+
+    let mut entries = Vec::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = match format {
+            EdlFormat::Csv => line.split(',').map(|f| f.trim().trim_matches('"')).collect(),
+            _ => line.split_whitespace().collect(),
+        };
+        // header row from a spreadsheet
+        if fields[0].eq_ignore_ascii_case("index") {
+            continue;
+        }
+        let number = |i: usize| -> io::Result<Option<usize>> {
+            match fields.get(i).filter(|f| !f.is_empty()) {
+                Some(f) => f.parse().map(Some)
+                    .map_err(|_| invalid(format!("EDL line {}: '{}' is not a number", line_no + 1, f))),
+                None => Ok(None),
+            }
+        };
+        entries.push(EdlEntry {
+            index: number(0)?.ok_or_else(|| invalid(format!("EDL line {}: missing frame index", line_no + 1)))?,
+            offset: number(1)?,
+            size: number(2)?,
+            frame_type: fields.get(3).and_then(|t| parse_type(t)),
+        });
+    }
+    Ok(entries)
+}
+
+pub fn read_edl(path: &Path) -> io::Result<Vec<EdlEntry>> {
+    parse_edl(&fs::read_to_string(path)?, EdlFormat::from_path(path))
+}
+
+/// Frames from `frame_table` in exactly the order the EDL lists them, with a
+/// warning on `clip` if rows don't match the frames they point at
+pub fn apply_edl(entries: &[EdlEntry], frame_table: &[Frame], clip: &ClipInfo) -> io::Result<Vec<Frame>> {
+    let mut mismatched = 0;
+    let frames = entries.iter().map(|entry| {
+        let frame = frame_table.get(entry.index)
+            .ok_or_else(|| invalid(format!("EDL frame {} is out of range, the source has {} frames", entry.index, frame_table.len())))?;
+        if entry.offset.is_some_and(|o| o != frame.offset)
+            || entry.size.is_some_and(|s| s != frame.size)
+            || entry.frame_type.as_ref().is_some_and(|t| *t != frame.frame_type) {
+            mismatched += 1;
+        }
+        Ok(frame.clone())
+    }).collect::<io::Result<Vec<Frame>>>()?;
+
+    if mismatched > 0 {
+        clip.warn(format!("{} EDL rows don't match the source frame table (different file or audio setting?), going by index", mismatched));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> Vec<Frame> {
+        [(0, FrameType::Video), (1, FrameType::Audio), (2, FrameType::Video), (3, FrameType::Void)].into_iter()
+            .map(|(i, frame_type)| Frame { index: i, offset: 4 + i * 20, size: 12 - i, rel_size: 0.0, frame_type, keyframe: false })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let frames = frames();
+        let order = vec![frames[2].clone(), frames[0].clone(), frames[1].clone(), frames[2].clone(), frames[3].clone()];
+        for format in [EdlFormat::Text, EdlFormat::Csv, EdlFormat::Json] {
+            let entries = parse_edl(&format_edl(&order, format), format).unwrap();
+            assert_eq!(entries, entries_from_frames(&order), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(EdlFormat::from_path(Path::new("cut.CSV")), EdlFormat::Csv);
+        assert_eq!(EdlFormat::from_path(Path::new("cut.json")), EdlFormat::Json);
+        assert_eq!(EdlFormat::from_path(Path::new("cut.txt")), EdlFormat::Text);
+        assert_eq!(EdlFormat::from_path(Path::new("cut")), EdlFormat::Text);
+    }
+
+    #[test]
+    fn hand_written_lists() {
+        let entries = parse_edl("# my cut\n3\n\n1 24\n", EdlFormat::Text).unwrap();
+        assert_eq!(entries.iter().map(|e| e.index).collect::<Vec<_>>(), vec![3, 1]);
+        assert_eq!((entries[0].offset, entries[1].offset), (None, Some(24)));
+
+        let entries = parse_edl("Index,Offset\n\"2\",,\n0,4,12,00dc\n", EdlFormat::Csv).unwrap();
+        assert_eq!(entries[0], EdlEntry { index: 2, offset: None, size: None, frame_type: None });
+        assert_eq!(entries[1].frame_type, Some(FrameType::Video));
+
+        let entries = parse_edl(r#"[{"index": 1}]"#, EdlFormat::Json).unwrap();
+        assert_eq!(entries[0], EdlEntry { index: 1, offset: None, size: None, frame_type: None });
+    }
+
+    #[test]
+    fn bad_lines() {
+        let err = parse_edl("0\nx\n", EdlFormat::Text).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(parse_edl("[{\"offset\": 1}]", EdlFormat::Json).is_err());
+    }
+
+    #[test]
+    fn apply_goes_by_index_and_warns_on_mismatches() {
+        let frames = frames();
+        let clip = ClipInfo::default();
+        let mut entries = entries_from_frames(&[frames[2].clone(), frames[0].clone()]);
+        let applied = apply_edl(&entries, &frames, &clip).unwrap();
+        assert_eq!(applied.iter().map(|f| f.index).collect::<Vec<_>>(), vec![2, 0]);
+        assert!(clip.warnings.borrow().is_empty());
+
+        entries[0].size = Some(999);
+        entries[1].frame_type = Some(FrameType::Audio);
+        let applied = apply_edl(&entries, &frames, &clip).unwrap();
+        assert_eq!(applied.iter().map(|f| f.index).collect::<Vec<_>>(), vec![2, 0]);
+        let warnings = clip.warnings.borrow();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("2 EDL rows"), "{}", warnings[0]);
+
+        let out_of_range = [EdlEntry { index: 4, offset: None, size: None, frame_type: None }];
+        assert!(apply_edl(&out_of_range, &frames, &clip).unwrap_err().to_string().contains("out of range"));
+    }
+}
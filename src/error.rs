@@ -0,0 +1,70 @@
+// error.rs - what can go wrong in tomatwo_seed, split so callers can tell a
+// bad setting from a broken file from a missing ffmpeg
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum TomatwoError {
+    /// the input has no chunk that processing needs, e.g. "movi"
+    MissingChunk(&'static str),
+    /// an `Opt` setting that can't work, `param` named like the cli option and recipe key
+    BadParam { param: &'static str, reason: String },
+    Io(io::Error),
+    /// ffmpeg or ffplay couldn't be run, or failed
+    Ffmpeg(String),
+}
+
+pub type Result<T> = std::result::Result<T, TomatwoError>;
+
+impl TomatwoError {
+    pub fn bad_param<S: Into<String>>(param: &'static str, reason: S) -> TomatwoError {
+        TomatwoError::BadParam { param, reason: reason.into() }
+    }
+
+    /// The closest `io::ErrorKind`, e.g. to spot a closed pipe
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            TomatwoError::MissingChunk(_) => io::ErrorKind::InvalidData,
+            TomatwoError::BadParam { .. } => io::ErrorKind::InvalidInput,
+            TomatwoError::Io(e) => e.kind(),
+            TomatwoError::Ffmpeg(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for TomatwoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TomatwoError::MissingChunk(id) => write!(f, "Not a usable AVI, there's no '{}' chunk", id),
+            TomatwoError::BadParam { param, reason } => write!(f, "Bad {}: {}", param, reason),
+            TomatwoError::Io(e) => write!(f, "{}", e),
+            TomatwoError::Ffmpeg(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TomatwoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TomatwoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TomatwoError {
+    fn from(e: io::Error) -> TomatwoError {
+        TomatwoError::Io(e)
+    }
+}
+
+/// For the cli and anything else that deals in `io::Result`
+impl From<TomatwoError> for io::Error {
+    fn from(e: TomatwoError) -> io::Error {
+        match e {
+            TomatwoError::Io(e) => e,
+            other => io::Error::new(other.kind(), other),
+        }
+    }
+}
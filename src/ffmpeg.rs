@@ -0,0 +1,240 @@
+// ffmpeg.rs - everything that shells out to ffmpeg
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::avi::{self, AviLayout, StreamFormat};
+use super::error::{Result, TomatwoError};
+use super::frames::Frames;
+
+fn ffmpeg_failed(what: &str) -> TomatwoError {
+    TomatwoError::Ffmpeg(format!("FFmpeg failed to {}", what))
+}
+
+fn run(program: &str, args: &[String]) -> Result<std::process::ExitStatus> {
+    Command::new(program).args(args).status()
+        .map_err(|e| TomatwoError::Ffmpeg(format!("Couldn't run {}, is it installed and on PATH? ({})", program, e)))
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Transcode settings for getting a video into a moshable AVI
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrepOptions {
+    /// ffmpeg video encoder, libxvid gives the classic mosh
+    pub codec: String,
+    /// -q:v, 1 (best) to 31
+    pub quality: u32,
+    /// max frames between keyframes, fewer keyframes means longer smears
+    pub keyint: Option<u32>,
+    pub fps: Option<f64>,
+    /// scale to this width, keeping the aspect ratio
+    pub width: Option<u32>,
+    /// uncompressed 16-bit audio, which the audio-reactive options can read
+    pub pcm_audio: bool,
+}
+
+impl Default for PrepOptions {
+    fn default() -> Self {
+        PrepOptions {
+            codec: "libxvid".to_string(),
+            quality: 2,
+            keyint: None,
+            fps: None,
+            width: None,
+            pcm_audio: false,
+        }
+    }
+}
+
+pub fn avi_args(input: &Path, output: &Path, prep: &PrepOptions) -> Vec<String> {
+    let mut args = vec!["-i".to_string(), path_str(input), "-c:v".to_string(), prep.codec.clone()];
+    args.extend(["-pix_fmt", "yuv420p"].map(String::from));
+    args.extend(["-q:v".to_string(), prep.quality.to_string()]);
+    if let Some(keyint) = prep.keyint {
+        args.extend(["-g".to_string(), keyint.to_string()]);
+    }
+    if let Some(fps) = prep.fps {
+        args.extend(["-r".to_string(), fps.to_string()]);
+    }
+    if let Some(width) = prep.width {
+        args.extend(["-vf".to_string(), format!("scale={}:-2", width)]);
+    }
+    if prep.pcm_audio {
+        args.extend(["-c:a", "pcm_s16le"].map(String::from));
+    } else {
+        args.extend(["-q:a", "0"].map(String::from));
+    }
+    args.extend(["-f".to_string(), "avi".to_string(), "-y".to_string(), path_str(output)]);
+    args
+}
+
+pub fn mp4_args(input: &Path, output: &Path, fast: bool) -> Vec<String> {
+    vec![
+        "-i", &path_str(input),
+        "-c:v", "libx264",
+        "-f", "mp4",
+        "-preset", if fast { "ultrafast" } else { "slow" },
+        "-crf", if fast { "0" } else { "17" },
+        "-pix_fmt", "yuv420p",
+        &path_str(output),
+    ].into_iter().map(String::from).collect()
+}
+
+/// Where prepped AVIs go: a 🍅/ folder next to the input, named after the whole
+/// file name so a.mp4 and a.mov don't prep into the same a.avi
+pub fn prep_output(input: &Path) -> PathBuf {
+    let file_name = input.file_name().unwrap_or_default();
+    let parent = input.parent().unwrap_or(Path::new("🍅"));
+    let is_avi = input.extension().is_some_and(|e| e.eq_ignore_ascii_case("avi"));
+    let name = if is_avi { PathBuf::from(file_name) } else { PathBuf::from(format!("{}.avi", file_name.to_string_lossy())) };
+    parent.join("🍅").join(name)
+}
+
+pub fn to_avi(input: &Path, output: &Path, prep: &PrepOptions) -> Result<()> {
+    if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let status = run("ffmpeg", &avi_args(input, output, prep))?;
+    if status.success() { Ok(()) } else { Err(ffmpeg_failed("transcode to avi")) }
+}
+
+/// Prep `input` into `prep_output(input)` with default settings, reusing an earlier one unless `force`.
+/// The flag says whether an earlier one was reused, for the caller to tell the user
+pub fn ffmpeg_to_avi(input: &Path, force: bool) -> Result<(PathBuf, bool)> {
+    let output = prep_output(input);
+    if !force && output.exists() {
+        return Ok((output, true));
+    }
+    to_avi(input, &output, &PrepOptions::default())?;
+    Ok((output, false))
+}
+
+pub fn to_mp4(input: &Path, output: &Path, fast: bool) -> Result<()> {
+    let status = run("ffmpeg", &mp4_args(input, output, fast))?;
+    if status.success() { Ok(()) } else { Err(ffmpeg_failed("convert to mp4")) }
+}
+
+pub fn ffmpeg_to_mp4(input: &Path, fast: bool) -> Result<PathBuf> {
+    let output = input.with_extension("mp4");
+    to_mp4(input, &output, fast)?;
+    Ok(output)
+}
+
+pub fn ffmpeg_list_codecs() -> Result<()> {
+    run("ffmpeg", &["-codecs".to_string()])?;
+    Ok(())
+}
+
+pub fn try_ffplay(path: &Path) -> Result<()> {
+    run("ffplay", &[path_str(path)])?;
+    Ok(())
+}
+
+/// ffmpeg encoder (and fourcc tag to force) that produces frames compatible with `fourcc`,
+/// None if there's no known one
+pub fn encoder_for(fourcc: &[u8; 4]) -> Option<(&'static str, Option<&'static str>)> {
+    let mut fourcc = *fourcc;
+    fourcc.make_ascii_uppercase();
+    match &fourcc {
+        b"XVID" => Some(("mpeg4", Some("XVID"))),
+        b"DIVX" | b"DX50" => Some(("mpeg4", Some("DIVX"))),
+        b"FMP4" | b"MP4V" => Some(("mpeg4", Some("FMP4"))),
+        b"MJPG" => Some(("mjpeg", None)),
+        b"H264" | b"X264" | b"AVC1" => Some(("libx264", Some("H264"))),
+        _ => None,
+    }
+}
+
+/// Encode a still image as a one frame AVI (a single keyframe) with the codec,
+/// size and frame rate of `like`, so its frames can be mixed with `like`'s.
+/// Returns the warnings, e.g. when the codec had to be guessed
+pub fn image_to_avi(image: &Path, output: &Path, like: &AviLayout) -> Result<Vec<String>> {
+    let video = like.video_stream()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Motion source has no video stream"))?;
+    let (fourcc, width, height) = match &video.format {
+        StreamFormat::Video(bmp) => (bmp.compression, bmp.width.unsigned_abs(), bmp.height.unsigned_abs()),
+        _ => (video.header.fcc_handler, like.main_header.width, like.main_header.height),
+    };
+    let mut warnings = Vec::new();
+    let (encoder, tag) = encoder_for(&fourcc).unwrap_or_else(|| {
+        let warning = format!("no encoder known for {}, using XVID, the frames may not mix", avi::fourcc_str(&fourcc));
+        eprintln!("> Warning: {}", warning);
+        warnings.push(warning);
+        ("mpeg4", Some("XVID"))
+    });
+    eprintln!("> Encoding image as {} ({}) {}x{} @ {:.3} fps", avi::fourcc_str(&fourcc), encoder, width, height, like.fps());
+
+    let scale = format!("scale={}:{}", width, height);
+    let fps = format!("{}", like.fps());
+    let image = path_str(image);
+    let output = path_str(output);
+    let mut args = vec![
+        "-i", &image,
+        "-frames:v", "1",
+        "-vf", &scale,
+        "-r", &fps,
+        "-c:v", encoder,
+        "-pix_fmt", "yuv420p",
+        "-q:v", "2",
+        "-an",
+        "-f", "avi",
+        "-y",
+    ];
+    if let Some(tag) = tag {
+        args.extend(["-vtag", tag]);
+    }
+    args.push(&output);
+
+    let status = run("ffmpeg", &args.into_iter().map(String::from).collect::<Vec<_>>())?;
+    if status.success() { Ok(warnings) } else { Err(ffmpeg_failed("encode the image")) }
+}
+
+/// Default output name for `image_mosh`, next to the motion source
+pub fn image_mosh_output(image: &Path, motion: &Path) -> PathBuf {
+    let stem = |p: &Path| p.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    motion.with_file_name(format!("{}-melt-{}.avi", stem(image), stem(motion)))
+}
+
+/// The "image melting with someone else's motion" effect: `image` encoded as a
+/// single keyframe, followed by every delta frame of `motion` with its keyframes removed.
+/// Returns the output path and the warnings from encoding the image
+pub fn image_mosh(image: &Path, motion: &Path, output: &Path) -> Result<(PathBuf, Vec<String>)> {
+    let motion_frames = Frames::open(motion)?;
+
+    let temp_dir = tempfile::tempdir()?;
+    let image_avi = temp_dir.path().join("image.avi");
+    let warnings = image_to_avi(image, &image_avi, motion_frames.layout())?;
+
+    let mut out = Frames::open(&image_avi)?;
+    out.retain(|f| f.is_video());
+    let video_id = out.first()
+        .map(|f| f.id)
+        .ok_or_else(|| TomatwoError::Ffmpeg("FFmpeg produced no video frame for the image".to_string()))?;
+
+    for frame in motion_frames.iter().filter(|f| f.is_deltaframe()) {
+        let mut frame = frame.clone();
+        frame.id = video_id;
+        out.push(frame);
+    }
+
+    out.write(output)?;
+    eprintln!("> Melted {:?} with {} motion frames from {:?} -> {:?}", image, out.len() - 1, motion, output);
+    Ok((output.to_path_buf(), warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prep_output_keeps_the_extension_apart() {
+        assert_eq!(prep_output(Path::new("clips/a.mp4")), Path::new("clips/🍅/a.mp4.avi"));
+        assert_eq!(prep_output(Path::new("clips/a.mov")), Path::new("clips/🍅/a.mov.avi"));
+        assert_eq!(prep_output(Path::new("clips/a.AVI")), Path::new("clips/🍅/a.AVI"));
+        assert_eq!(prep_output(Path::new("a")), Path::new("🍅/a.avi"));
+    }
+}
@@ -0,0 +1,491 @@
+// frames.rs - aviglitch style frame collection
+
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::ops::{Bound, Index, Range, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+use memmap2::Mmap;
+
+use super::avi::{self, AviLayout, AVIIF_KEYFRAME};
+use super::output::write_atomic;
+
+type Buffer = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// One chunk from the movi list. The payload is shared with the buffer it came
+/// from until it is replaced, so cloning a frame is cheap.
+#[derive(Clone)]
+pub struct AviFrame {
+    pub id: [u8; 4],
+    pub flags: u32,
+    buffer: Buffer,
+    range: Range<usize>,
+}
+
+impl AviFrame {
+    pub fn new(id: [u8; 4], flags: u32, data: Vec<u8>) -> Self {
+        let range = 0..data.len();
+        AviFrame { id, flags, buffer: Arc::new(data), range }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &(*self.buffer).as_ref()[self.range.clone()]
+    }
+
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.range = 0..data.len();
+        self.buffer = Arc::new(data);
+    }
+
+    pub fn size(&self) -> usize {
+        self.range.len()
+    }
+
+    /// Stream number from the first two characters of the chunk id
+    pub fn stream(&self) -> Option<u16> {
+        std::str::from_utf8(&self.id[..2]).ok()?.parse().ok()
+    }
+
+    pub fn is_video(&self) -> bool {
+        &self.id[2..] == b"dc" || &self.id[2..] == b"db"
+    }
+
+    pub fn is_audio(&self) -> bool {
+        &self.id[2..] == b"wb"
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & AVIIF_KEYFRAME != 0
+    }
+
+    pub fn is_deltaframe(&self) -> bool {
+        self.is_video() && !self.is_keyframe()
+    }
+
+    pub fn set_keyframe(&mut self, keyframe: bool) {
+        if keyframe {
+            self.flags |= AVIIF_KEYFRAME;
+        } else {
+            self.flags &= !AVIIF_KEYFRAME;
+        }
+    }
+}
+
+impl std::fmt::Debug for AviFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AviFrame")
+            .field("id", &avi::fourcc_str(&self.id))
+            .field("flags", &self.flags)
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+/// Whether a movi chunk is a frame, rather than padding or a chunk index
+pub(crate) fn is_frame_chunk(id: &[u8; 4]) -> bool {
+    id != b"JUNK" && &id[..2] != b"ix"
+}
+
+/// The frames of a parsed AVI, usable as a collection you can slice, concat,
+/// splice, insert into and delete from, then write back out as a valid file.
+/// Modelled on ucnv/aviglitch.
+#[derive(Clone)]
+pub struct Frames {
+    source: Buffer,
+    layout: Arc<AviLayout>,
+    frames: Vec<AviFrame>,
+}
+
+impl Frames {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Frames> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        Frames::from_buffer(Arc::new(mmap))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<Frames> {
+        Frames::from_buffer(Arc::new(data))
+    }
+
+    fn from_buffer(source: Buffer) -> io::Result<Frames> {
+        let data = (*source).as_ref();
+        let layout = avi::parse_avi(data)?;
+
+        // idx1 entries are in movi order, so match them up by position
+        let mut index = layout.index.iter().filter(|e| &e.id != b"rec ").peekable();
+        let frames = layout.movi.children.iter()
+            .filter(|c| is_frame_chunk(&c.id))
+            .map(|c| {
+                let flags = match index.peek() {
+                    Some(entry) if entry.id == c.id => index.next().map_or(0, |e| e.flags),
+                    _ => 0,
+                };
+                AviFrame { id: c.id, flags, buffer: source.clone(), range: c.data.clone() }
+            })
+            .collect::<Vec<_>>();
+
+        let mut frames = Frames { source: source.clone(), layout: Arc::new(layout), frames };
+        // no usable index: assume only the first video frame is a keyframe
+        if frames.layout.index.is_empty() {
+            if let Some(first) = frames.frames.iter_mut().find(|f| f.is_video()) {
+                first.set_keyframe(true);
+            }
+        }
+        Ok(frames)
+    }
+
+    pub fn layout(&self) -> &AviLayout {
+        &self.layout
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&AviFrame> {
+        self.frames.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut AviFrame> {
+        self.frames.get_mut(index)
+    }
+
+    pub fn first(&self) -> Option<&AviFrame> {
+        self.frames.first()
+    }
+
+    pub fn last(&self) -> Option<&AviFrame> {
+        self.frames.last()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, AviFrame> {
+        self.frames.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, AviFrame> {
+        self.frames.iter_mut()
+    }
+
+    pub fn as_slice(&self) -> &[AviFrame] {
+        &self.frames
+    }
+
+    pub fn video_count(&self) -> usize {
+        self.frames.iter().filter(|f| f.is_video()).count()
+    }
+
+    pub fn keyframe_count(&self) -> usize {
+        self.frames.iter().filter(|f| f.is_video() && f.is_keyframe()).count()
+    }
+
+    fn bounds<R: RangeBounds<usize>>(&self, range: R) -> Range<usize> {
+        let start = match range.start_bound() {
+            Bound::Included(&s) => s,
+            Bound::Excluded(&s) => s + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&e) => e + 1,
+            Bound::Excluded(&e) => e,
+            Bound::Unbounded => self.frames.len(),
+        };
+        let end = end.min(self.frames.len());
+        start.min(end)..end
+    }
+
+    /// New collection with a copy of the frames in `range`, sharing headers and data
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> Frames {
+        let range = self.bounds(range);
+        Frames { source: self.source.clone(), layout: self.layout.clone(), frames: self.frames[range].to_vec() }
+    }
+
+    /// Append all frames of `other`
+    pub fn concat(&mut self, other: &Frames) -> &mut Self {
+        self.frames.extend_from_slice(&other.frames);
+        self
+    }
+
+    /// Insert all frames of `other` before position `at`
+    pub fn insert(&mut self, at: usize, other: &Frames) -> &mut Self {
+        let at = at.min(self.frames.len());
+        self.frames.splice(at..at, other.frames.iter().cloned());
+        self
+    }
+
+    /// Replace the frames in `range` with the frames of `other`, returning the removed ones
+    pub fn splice<R: RangeBounds<usize>>(&mut self, range: R, other: &Frames) -> Frames {
+        let range = self.bounds(range);
+        let removed = self.frames.splice(range, other.frames.iter().cloned()).collect();
+        Frames { source: self.source.clone(), layout: self.layout.clone(), frames: removed }
+    }
+
+    /// Remove the frames in `range`, returning them
+    pub fn delete<R: RangeBounds<usize>>(&mut self, range: R) -> Frames {
+        let range = self.bounds(range);
+        let removed = self.frames.drain(range).collect();
+        Frames { source: self.source.clone(), layout: self.layout.clone(), frames: removed }
+    }
+
+    pub fn push(&mut self, frame: AviFrame) -> &mut Self {
+        self.frames.push(frame);
+        self
+    }
+
+    pub fn retain<F: FnMut(&AviFrame) -> bool>(&mut self, f: F) -> &mut Self {
+        self.frames.retain(f);
+        self
+    }
+
+    /// Turn every video keyframe after the first `keep_first` into a delta frame
+    /// by giving it the previous delta frame's data, or just clearing its
+    /// keyframe flag when there's no delta before it. Frame count stays the same.
+    pub fn mutate_keyframes_into_deltas(&mut self, keep_first: usize) -> &mut Self {
+        let mut seen = 0;
+        let mut prev_delta: Option<AviFrame> = None;
+        for frame in self.frames.iter_mut().filter(|f| f.is_video()) {
+            if frame.is_keyframe() {
+                seen += 1;
+                if seen <= keep_first {
+                    continue;
+                }
+                if let Some(prev) = &prev_delta {
+                    frame.buffer = prev.buffer.clone();
+                    frame.range = prev.range.clone();
+                }
+                frame.set_keyframe(false);
+            } else {
+                prev_delta = Some(frame.clone());
+            }
+        }
+        self
+    }
+
+    /// Same headers and data, frames picked by index (repeats allowed)
+    pub fn reorder(&self, order: &[usize]) -> Frames {
+        let frames = order.iter().filter_map(|&i| self.frames.get(i).cloned()).collect();
+        Frames { source: self.source.clone(), layout: self.layout.clone(), frames }
+    }
+
+    /// hdrl with avih total frames and video strh length patched to the current frames
+    fn patched_hdrl(&self) -> Vec<u8> {
+        let data = (*self.source).as_ref();
+        let hdrl = &self.layout.hdrl;
+        let mut out = data[hdrl.offset..hdrl.data.end].to_vec();
+        let video_frames = self.video_count() as u32;
+
+        let mut patch = |abs: usize, value: u32| {
+            if let Some(field) = abs.checked_sub(hdrl.offset).and_then(|p| out.get_mut(p..p + 4)) {
+                field.copy_from_slice(&value.to_le_bytes());
+            }
+        };
+        patch(self.layout.avih_offset + 16, video_frames);
+        for stream in self.layout.streams.iter().filter(|s| &s.header.fcc_type == b"vids") {
+            patch(stream.strh_offset + 32, video_frames);
+        }
+        // LIST size, in case the source had a bad one
+        let list_size = (out.len() - 8) as u32;
+        out[4..8].copy_from_slice(&list_size.to_le_bytes());
+        out
+    }
+
+    /// Write a complete AVI with a rebuilt movi list and idx1
+    pub fn write_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let hdrl = self.patched_hdrl();
+        let movi_size: usize = 4 + self.frames.iter().map(|f| 8 + f.size() + f.size() % 2).sum::<usize>();
+        let idx1_size = 16 * self.frames.len();
+        let riff_size = 4 + hdrl.len() + hdrl.len() % 2 + 8 + movi_size + 8 + idx1_size;
+        // every other size and offset is smaller, so if this one fits they all do
+        let riff_size = u32::try_from(riff_size).map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+            format!("Output would be {} bytes, more than an AVI can hold (4 GiB)", riff_size + 8)))?;
+
+        output.write_all(b"RIFF")?;
+        output.write_all(&riff_size.to_le_bytes())?;
+        output.write_all(b"AVI ")?;
+        output.write_all(&hdrl)?;
+        if hdrl.len() % 2 == 1 {
+            output.write_all(&[0])?;
+        }
+
+        output.write_all(b"LIST")?;
+        output.write_all(&(movi_size as u32).to_le_bytes())?;
+        output.write_all(b"movi")?;
+        for frame in &self.frames {
+            output.write_all(&frame.id)?;
+            output.write_all(&(frame.size() as u32).to_le_bytes())?;
+            output.write_all(frame.data())?;
+            if frame.size() % 2 == 1 {
+                output.write_all(&[0])?;
+            }
+        }
+
+        output.write_all(b"idx1")?;
+        output.write_all(&(idx1_size as u32).to_le_bytes())?;
+        // offsets are relative to the 'movi' fourcc
+        let mut offset = 4u32;
+        for frame in &self.frames {
+            output.write_all(&frame.id)?;
+            output.write_all(&frame.flags.to_le_bytes())?;
+            output.write_all(&offset.to_le_bytes())?;
+            output.write_all(&(frame.size() as u32).to_le_bytes())?;
+            offset += (8 + frame.size() + frame.size() % 2) as u32;
+        }
+        Ok(())
+    }
+
+    /// Write the AVI to `path`, through a temp file that's renamed into place
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomic(path.as_ref(), |temp| {
+            let mut output = BufWriter::new(File::create(temp)?);
+            self.write_to(&mut output)?;
+            output.flush()
+        })
+    }
+}
+
+impl Index<usize> for Frames {
+    type Output = AviFrame;
+
+    fn index(&self, index: usize) -> &AviFrame {
+        &self.frames[index]
+    }
+}
+
+impl<'a> IntoIterator for &'a Frames {
+    type Item = &'a AviFrame;
+    type IntoIter = std::slice::Iter<'a, AviFrame>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames.iter()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    pub(crate) fn list(id: &[u8; 4], list_type: &[u8; 4], children: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = list_type.to_vec();
+        children.iter().for_each(|c| data.extend(c));
+        chunk(id, &data)
+    }
+
+    /// An AVI with one video stream and `frames` (id, keyframe, data) in movi and idx1
+    pub(crate) fn avi(frames: &[(&[u8; 4], bool, Vec<u8>)]) -> Vec<u8> {
+        let mut strh = b"vids".to_vec();
+        strh.resize(56, 0);
+        let mut idx1 = Vec::new();
+        let mut offset = 4u32;
+        for (id, keyframe, data) in frames {
+            idx1.extend(*id);
+            idx1.extend((if *keyframe { AVIIF_KEYFRAME } else { 0 }).to_le_bytes());
+            idx1.extend(offset.to_le_bytes());
+            idx1.extend((data.len() as u32).to_le_bytes());
+            offset += (8 + data.len() + data.len() % 2) as u32;
+        }
+        list(b"RIFF", b"AVI ", &[
+            list(b"LIST", b"hdrl", &[
+                chunk(b"avih", &[0; 56]),
+                list(b"LIST", b"strl", &[chunk(b"strh", &strh), chunk(b"strf", &[0; 40])]),
+            ]),
+            list(b"LIST", b"movi", &frames.iter().map(|(id, _, data)| chunk(id, data)).collect::<Vec<_>>()),
+            chunk(b"idx1", &idx1),
+        ])
+    }
+
+    fn sample() -> Frames {
+        Frames::from_bytes(avi(&[
+            (b"00dc", true, vec![1; 5]),
+            (b"00dc", false, vec![2; 4]),
+            (b"01wb", false, vec![9; 3]),
+            (b"00dc", false, vec![3; 6]),
+            (b"00dc", true, vec![4; 7]),
+            (b"00dc", false, vec![5; 2]),
+        ])).unwrap()
+    }
+
+    fn written(frames: &Frames) -> Vec<u8> {
+        let mut out = Vec::new();
+        frames.write_to(&mut out).unwrap();
+        out
+    }
+
+    fn datas(frames: &Frames) -> Vec<u8> {
+        frames.iter().map(|f| f.data()[0]).collect()
+    }
+
+    #[test]
+    fn write_round_trips() {
+        let frames = sample();
+        let out = written(&frames);
+        let layout = avi::parse_avi(&out).unwrap();
+
+        assert_eq!(layout.riff.size + 8, out.len());
+        assert_eq!(layout.main_header.total_frames, 5);
+        assert_eq!(layout.video_stream().unwrap().header.length, 5);
+
+        // idx1 points at each chunk, relative to the 'movi' fourcc, and chunks start on even bytes
+        let movi_fourcc = layout.movi.offset + 8;
+        assert_eq!(layout.index.len(), layout.movi.children.len());
+        for (entry, chunk) in layout.index.iter().zip(&layout.movi.children) {
+            assert_eq!(entry.offset as usize, chunk.offset - movi_fourcc);
+            assert_eq!(entry.size as usize, chunk.size);
+            assert_eq!(chunk.offset % 2, 0);
+        }
+
+        let again = Frames::from_bytes(out).unwrap();
+        assert_eq!(datas(&again), datas(&frames));
+        assert_eq!(again.iter().map(|f| f.size()).collect::<Vec<_>>(), vec![5, 4, 3, 6, 7, 2]);
+        assert_eq!(again.iter().map(|f| f.is_keyframe()).collect::<Vec<_>>(), vec![true, false, false, false, true, false]);
+    }
+
+    #[test]
+    fn edits_write_what_they_say() {
+        let frames = sample();
+        assert_eq!(datas(&frames.slice(1..3)), vec![2, 9]);
+        assert_eq!(datas(&frames.reorder(&[5, 5, 0, 99])), vec![5, 5, 1]);
+
+        let mut edited = frames.clone();
+        let removed = edited.delete(..2);
+        assert_eq!((datas(&removed), datas(&edited)), (vec![1, 2], vec![9, 3, 4, 5]));
+        let removed = edited.splice(1..=2, &frames.slice(..1));
+        assert_eq!((datas(&removed), datas(&edited)), (vec![3, 4], vec![9, 1, 5]));
+
+        let layout = avi::parse_avi(&written(&edited)).unwrap();
+        assert_eq!(layout.main_header.total_frames, 2);
+        assert_eq!(Frames::from_bytes(written(&edited)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn mutate_keyframes_takes_the_previous_delta() {
+        let mut frames = sample();
+        frames.mutate_keyframes_into_deltas(1);
+        assert_eq!(datas(&frames), vec![1, 2, 9, 3, 3, 5]);
+        assert_eq!(frames.keyframe_count(), 1);
+
+        let mut frames = sample();
+        frames.mutate_keyframes_into_deltas(0);
+        assert_eq!(datas(&frames), vec![1, 2, 9, 3, 3, 5], "the first keyframe has no delta before it");
+        assert_eq!(frames.keyframe_count(), 0);
+    }
+
+    #[test]
+    fn too_big_for_an_avi() {
+        let frames = Frames::from_bytes(avi(&[(b"00dc", true, vec![0; 1 << 20])])).unwrap();
+        let err = frames.reorder(&vec![0; 4097]).write_to(&mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -2,10 +2,13 @@ use eframe::egui;
 use std::sync::mpsc::{channel};
 use std::path::PathBuf;
 use std::thread;
-use super::models::{Gooey, ProcessState, ProcessingStep};
+use super::models::{Gooey, ProcessState, ProcessingStep, RetimeSettings};
 use super::video_processing::{ffmpeg_to_avi, spawn_try_ffplay, ffmpeg_to_mp4};
 use super::welcome_screen::{render_datamosh_guide, render_welcome_screen};
-use tomatwo_seed::{Opt, process_video, extract_frame_data, simulate_processing};
+use tomatwo_seed::{Opt, Beats, MarkovModel, Overwrite, Signal, Order, Ramp, process_steps, extract_frame_data, simulate_processing, detect_scene_cuts, SCENE_CUT_SPIKE};
+use tomatwo_seed::recipe::Recipe;
+use tomatwo_seed::script::check_script;
+use tomatwo_seed::ffmpeg::{image_mosh, image_mosh_output};
 
 
 impl Gooey {
@@ -13,54 +16,90 @@ impl Gooey {
         Self::default()
     }
 
+    /// Every step's options, with retime on the last one, as `process_steps` and recipes want them
+    fn step_opts(&self, input: PathBuf) -> Vec<Opt> {
+        let mut opts: Vec<Opt> = self.processing_steps.iter().map(|step| step.to_opt(input.clone())).collect();
+        if let Some(last) = opts.last_mut() {
+            last.retime = self.retime.retime();
+            last.name_template = Some(self.output.template.clone()).filter(|t| !t.trim().is_empty());
+            last.overwrite = self.output.overwrite;
+        }
+        opts
+    }
+
+    fn save_recipe(&self) {
+        if let Some(path) = rfd::FileDialog::new().add_filter("Recipe", &["toml", "json"]).set_file_name("recipe.toml").save_file() {
+            if let Err(e) = Recipe::from_steps(&self.step_opts(PathBuf::new())).write(&path) {
+                eprintln!("Error saving recipe: {:?}", e);
+            }
+        }
+    }
+
+    fn load_recipe(&mut self) {
+        let Some(path) = rfd::FileDialog::new().add_filter("Recipe", &["toml", "json"]).pick_file() else { return };
+        let base_dir = path.parent().unwrap_or(std::path::Path::new("")).to_path_buf();
+        let steps = Recipe::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|recipe| recipe.to_steps(&PathBuf::new(), &base_dir));
+        match steps {
+            Ok(steps) => {
+                self.processing_steps = steps.iter().map(ProcessingStep::from_opt).collect();
+                self.retime = RetimeSettings::from_retime(steps.last().and_then(|s| s.retime.as_ref()));
+                self.selected_step = Some(0);
+                self.frame_data_needs_update = true;
+            }
+            Err(e) => eprintln!("Error loading recipe: {}", e),
+        }
+    }
+
     fn process_video(&self, preview: bool) {
+        let Some(input) = self.avi_path.clone() else { return };
         let tx = self.tx.clone();
-        let avi_path = self.avi_path.clone().unwrap();
-        let steps = self.processing_steps.clone();
+        let mut steps = self.step_opts(input);
+        if let Some(last) = steps.last_mut() {
+            last.preview = preview;
+        }
     
+        // sends fail once the window is closed, and then nobody needs the state anyway
         thread::spawn(move || {
-            tx.send(ProcessState::Datamoshing).unwrap();
-            let mut current_input = avi_path;
-    
-            for (i, step) in steps.iter().enumerate() {
-                let opt = Opt {
-                    input: current_input.clone(),
-                    mode: step.mode.clone(),
-                    countframes: step.count_frames,
-                    positframes: step.posit_frames,
-                    audio: false,
-                    firstframe: false,
-                    kill: step.kill,
-                    kill_rel: step.kill_rel * step.kill_rel, // exp slider
-                    multiply: step.multiply,
-                    preview: preview && i == steps.len() - 1, // Only preview on the last step
-                };
-    
-                match process_video(&opt) {
-                    Ok(output_path) => {
-                        if i == steps.len() - 1 {
-                            if preview {
-                                tx.send(ProcessState::Idle).unwrap();
-                            } else {
-                                tx.send(ProcessState::Done(output_path.clone())).unwrap();
-                            }
-                        }
-                        current_input = output_path;
+            let _ = tx.send(ProcessState::Datamoshing);
+            match process_steps(&steps) {
+                Ok(output_path) => {
+                    if preview {
+                        let _ = tx.send(ProcessState::Idle);
+                    } else {
+                        let _ = tx.send(ProcessState::Done(output_path));
                     }
-                    Err(e) => {
-                        if preview && (e.kind() == std::io::ErrorKind::BrokenPipe) {
-                            tx.send(ProcessState::Idle).unwrap();
-                        } else {
-                            eprintln!("Error processing video: {:?}", e);
-                            tx.send(ProcessState::Error).unwrap();
-                        }
-                        return;
+                }
+                Err(e) => {
+                    if preview && (e.kind() == std::io::ErrorKind::BrokenPipe) {
+                        let _ = tx.send(ProcessState::Idle);
+                    } else {
+                        eprintln!("Error processing video: {:?}", e);
+                        let _ = tx.send(ProcessState::Error);
                     }
                 }
             }
         });
     }
 
+    fn melt_image(&self, image: PathBuf) {
+        let Some(motion) = self.avi_path.clone() else { return };
+        let tx = self.tx.clone();
+
+        thread::spawn(move || {
+            let _ = tx.send(ProcessState::Datamoshing);
+            let output = image_mosh_output(&image, &motion);
+            match image_mosh(&image, &motion, &output) {
+                Ok((output_path, _)) => { let _ = tx.send(ProcessState::Done(output_path)); }
+                Err(e) => {
+                    eprintln!("Error melting image: {:?}", e);
+                    let _ = tx.send(ProcessState::Error);
+                }
+            }
+        });
+    }
+
     fn extract_frame_data(&mut self) {
         if let Some(avi_path) = &self.avi_path {
             match extract_frame_data(avi_path) {
@@ -82,16 +121,8 @@ impl Gooey {
         if let (Some((original_frames, max_size)), Some(selected)) = (&self.original_frame_data, self.selected_step) {
             let steps_to_apply: Vec<Opt> = self.processing_steps[0..selected].iter().map(|step| {
                 Opt {
-                    input: PathBuf::new(), // Dummy path
-                    mode: step.mode.clone(),
-                    countframes: step.count_frames,
-                    positframes: step.posit_frames,
-                    audio: false,
-                    firstframe: false,
-                    kill: step.kill,
                     kill_rel: step.kill_rel,
-                    multiply: step.multiply,
-                    preview: false,
+                    ..step.to_opt(PathBuf::new()) // Dummy path
                 }
             }).collect();
     
@@ -133,15 +164,19 @@ impl eframe::App for Gooey {
                             return;
                         }
                         println!("Converting input video to AVI...");
-                        self.tx.send(ProcessState::Converting).unwrap();
-                        match ffmpeg_to_avi(&path, false, &mut self.using_existing) {
-                            Ok(avi_path) => {
+                        let _ = self.tx.send(ProcessState::Converting);
+                        match ffmpeg_to_avi(&path, false) {
+                            Ok((avi_path, using_existing)) => {
+                                if using_existing {
+                                    println!("Using the AVI prepped earlier: {:?}", avi_path);
+                                }
                                 self.avi_path = Some(avi_path);
-                                self.tx.send(ProcessState::Idle).unwrap();
+                                self.using_existing = using_existing;
+                                let _ = self.tx.send(ProcessState::Idle);
                             },
                             Err(e) => {
                                 eprintln!("Error preparing input video as AVI: {:?}", e);
-                                self.tx.send(ProcessState::Error).unwrap();
+                                let _ = self.tx.send(ProcessState::Error);
                             }
                         }
                         self.extract_frame_data();
@@ -149,7 +184,7 @@ impl eframe::App for Gooey {
                     }
                 }
                 if let Some(path) = &self.input_path {
-                    ui.label(format!("Selected: {}", path.file_name().unwrap().to_string_lossy()));
+                    ui.label(format!("Selected: {}", path.file_name().unwrap_or_default().to_string_lossy()));
                 }
             });
         });
@@ -171,6 +206,40 @@ impl eframe::App for Gooey {
                 self.selected_step = Some(self.processing_steps.len() - 1);
                 self.frame_data_needs_update = true;
             }
+            ui.horizontal(|ui| {
+                if ui.button("💾 save recipe").clicked() {
+                    self.save_recipe();
+                }
+                if ui.button("📂 load recipe").clicked() {
+                    self.load_recipe();
+                }
+            });
+
+            ui.separator();
+            ui.checkbox(&mut self.retime.enabled, "retime output");
+            if self.retime.enabled {
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.retime.by_duration, true, "seconds");
+                    ui.radio_value(&mut self.retime.by_duration, false, "frames");
+                });
+                if self.retime.by_duration {
+                    ui.add(egui::DragValue::new(&mut self.retime.seconds).clamp_range(0.1..=3600.0).speed(0.1).suffix(" s"));
+                    ui.checkbox(&mut self.retime.rewrite_fps, "change frame rate instead");
+                } else {
+                    ui.add(egui::DragValue::new(&mut self.retime.frames).clamp_range(1..=100_000).suffix(" frames"));
+                }
+            }
+
+            ui.separator();
+            ui.label("output name");
+            ui.add(egui::TextEdit::singleline(&mut self.output.template).hint_text("{stem}-{params}"))
+                .on_hover_text("{stem} {mode} {params} {seed} {date} {n}, saved next to the source");
+            ui.label("if it exists");
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.output.overwrite, Overwrite::Increment, "number");
+                ui.radio_value(&mut self.output.overwrite, Overwrite::Replace, "overwrite");
+                ui.radio_value(&mut self.output.overwrite, Overwrite::Skip, "skip");
+            });
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -215,6 +284,15 @@ impl eframe::App for Gooey {
                     );
                 }
                 
+                // Mark detected scene cuts
+                for cut in detect_scene_cuts(frame_data, SCENE_CUT_SPIKE) {
+                    let x = rect.left() + (cut as f32 + 0.5) * bar_width;
+                    painter.line_segment(
+                        [egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())],
+                        egui::Stroke::new(1.0, egui::Color32::YELLOW)
+                    );
+                }
+
                 // Draw kill line using the current step's kill value
                 painter.line_segment(
                     [
@@ -227,6 +305,7 @@ impl eframe::App for Gooey {
                 ui.horizontal(|ui| {
                     ui.label("White: Frames that would be kept");
                     ui.label("Red: Frames that would be removed");
+                    ui.label("Yellow: Detected scene cuts");
                 });
                 ui.horizontal(|ui| {
                     ui.label(format!("Total video frames: {}", frame_data.len()));
@@ -274,17 +353,144 @@ impl eframe::App for Gooey {
                                 ui.selectable_value(&mut step.mode, "pulse".to_string(), "Pulse");
                                 ui.selectable_value(&mut step.mode, "jiggle".to_string(), "Jiggle");
                                 ui.selectable_value(&mut step.mode, "overlap".to_string(), "Overlap");
+                                ui.selectable_value(&mut step.mode, "markov".to_string(), "Markov walk");
+                                ui.selectable_value(&mut step.mode, "pingpong".to_string(), "Ping-pong");
+                                ui.selectable_value(&mut step.mode, "stutter".to_string(), "Stutter");
+                                ui.selectable_value(&mut step.mode, "stride".to_string(), "Stride");
+                                ui.selectable_value(&mut step.mode, "sort".to_string(), "Sort by size");
+                                ui.selectable_value(&mut step.mode, "rsort".to_string(), "Sort by size (desc)");
+                                ui.selectable_value(&mut step.mode, "blockshuffle".to_string(), "Block shuffle");
+                                ui.selectable_value(&mut step.mode, "echo".to_string(), "Echo");
+                                ui.selectable_value(&mut step.mode, "automosh".to_string(), "Auto-mosh");
+                                ui.selectable_value(&mut step.mode, "loop".to_string(), "Seamless loop");
+                                ui.selectable_value(&mut step.mode, "order".to_string(), "Order");
+                                ui.selectable_value(&mut step.mode, "script".to_string(), "Script");
                             });
                         if ui.button("🗑️").clicked() {
                             remove_step = true;
                         }
                     });
 
+                    if step.mode == "order" {
+                        ui.horizontal(|ui| {
+                            ui.label("Order");
+                            ui.add(egui::TextEdit::singleline(&mut step.order)
+                                .hint_text("0..120, 120*30, rev(121..200), shuffle(200..260)")
+                                .font(egui::TextStyle::Monospace)
+                                .desired_width(f32::INFINITY));
+                        });
+                        if let Err(e) = Order::parse(&step.order) {
+                            ui.colored_label(egui::Color32::RED, egui::RichText::new(e.pretty(&step.order)).monospace());
+                        }
+                    }
+
+                    if step.mode == "markov" {
+                        ui.horizontal(|ui| {
+                            ui.label("Next frame by");
+                            ui.radio_value(&mut step.markov, MarkovModel::Size, "Size");
+                            ui.radio_value(&mut step.markov, MarkovModel::Adjacency, "Adjacency");
+                        });
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut step.fixed_seed, "Seed");
+                            ui.add_enabled(step.fixed_seed, egui::DragValue::new(&mut step.seed));
+                        });
+                    }
+
+                    if step.mode == "pulse" || step.mode == "bloom" {
+                        ui.checkbox(&mut step.beat_sync, "On the beat (instead of every n frames)");
+                        if step.beat_sync {
+                            ui.horizontal(|ui| {
+                                ui.add_enabled(step.beat_list.is_none(), egui::DragValue::new(&mut step.bpm).clamp_range(1.0..=400.0).suffix(" bpm"));
+                                ui.add_enabled(step.beat_list.is_none(), egui::DragValue::new(&mut step.beat_offset).speed(0.01).suffix(" s offset"));
+                            });
+                            ui.horizontal(|ui| {
+                                if ui.button("beat list…").on_hover_text("Beat times in seconds, one per line. Audacity labels work").clicked() {
+                                    if let Some(path) = rfd::FileDialog::new().add_filter("Beat list", &["txt", "csv"]).pick_file() {
+                                        match Beats::read_list(&path) {
+                                            Ok(beats) => {
+                                                step.beat_list = Some(beats);
+                                                step.beat_file = Some(path);
+                                            }
+                                            Err(e) => eprintln!("Error reading beat list: {}", e),
+                                        }
+                                    }
+                                }
+                                if let (Some(path), Some(Beats::List(times))) = (&step.beat_file, &step.beat_list) {
+                                    ui.label(format!("{} beats from {}", times.len(), path.file_name().unwrap_or_default().to_string_lossy()));
+                                    if ui.small_button("✖").clicked() {
+                                        step.beat_file = None;
+                                        step.beat_list = None;
+                                    }
+                                }
+                            });
+                        }
+                    }
+
+                    if step.mode == "script" {
+                        egui::ScrollArea::vertical().max_height(240.0).show(ui, |ui| {
+                            ui.add(egui::TextEdit::multiline(&mut step.script)
+                                .code_editor()
+                                .hint_text("// frames, len, c, n, fps -> array of frame positions\nlet out = [];\nfor i in 0..len { out.push(len - 1 - i) }\nout")
+                                .desired_rows(10)
+                                .desired_width(f32::INFINITY));
+                        });
+                        if let Err(e) = check_script(&step.script) {
+                            ui.colored_label(egui::Color32::RED, egui::RichText::new(e).monospace());
+                        }
+                    }
+
                     ui.add(egui::Slider::new(&mut step.count_frames, 1..=100).text("Count Frames"));
                     ui.add(egui::Slider::new(&mut step.posit_frames, 1..=100).text("Position Frames"));
                     ui.add(egui::Slider::new(&mut step.kill, 0.0..=1.0).text("Kill Threshold"));
                     ui.add(egui::Slider::new(&mut step.kill_rel, -0.1..=10.0).text("Kill Relative"));
                     ui.add(egui::Slider::new(&mut step.multiply, 1..=10).text("Multiply"));
+                    ui.horizontal(|ui| {
+                        ui.label("Ramp");
+                        ui.radio_value(&mut step.ramp_kind, "flat".to_string(), "Flat");
+                        ui.radio_value(&mut step.ramp_kind, "curve".to_string(), "Curve");
+                        ui.radio_value(&mut step.ramp_kind, "size".to_string(), "By size");
+                        ui.radio_value(&mut step.ramp_kind, "loud".to_string(), "Loudness");
+                        ui.radio_value(&mut step.ramp_kind, "onset".to_string(), "Onsets");
+                    });
+                    match step.ramp_kind.as_str() {
+                        "curve" => {
+                            ui.horizontal(|ui| {
+                                ui.label("Rates over time");
+                                ui.add(egui::TextEdit::singleline(&mut step.ramp_curve).hint_text("1, 4, 1"));
+                            });
+                            if let Err(e) = format!("curve:{}", step.ramp_curve).parse::<Ramp>() {
+                                ui.colored_label(egui::Color32::RED, e);
+                            }
+                        }
+                        "size" => {
+                            ui.add(egui::Slider::new(&mut step.ramp_max, 0.0..=10.0).text("Rate for smallest frames"));
+                            ui.add(egui::Slider::new(&mut step.ramp_min, 0.0..=10.0).text("Rate for biggest frames"));
+                        }
+                        "loud" | "onset" => {
+                            ui.add(egui::Slider::new(&mut step.ramp_min, 0.0..=10.0).text("Rate for silence"));
+                            ui.add(egui::Slider::new(&mut step.ramp_max, 0.0..=10.0).text("Rate for the loudest / biggest hit"));
+                            ui.label("Needs PCM audio in the source avi");
+                        }
+                        _ => {}
+                    }
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut step.mutate, "Mutate keyframes into deltas");
+                        ui.add_enabled(step.mutate, egui::Slider::new(&mut step.keep_keyframes, 0..=20).text("Keep first"));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut step.scoped, "Only frames");
+                        ui.add_enabled(step.scoped, egui::DragValue::new(&mut step.scope_start));
+                        ui.label("to");
+                        ui.add_enabled(step.scoped, egui::DragValue::new(&mut step.scope_end));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut step.kill_on_audio, "Only kill on");
+                        ui.add_enabled_ui(step.kill_on_audio, |ui| {
+                            ui.radio_value(&mut step.kill_on.signal, Signal::Onset, "onsets");
+                            ui.radio_value(&mut step.kill_on.signal, Signal::Loudness, "loudness");
+                            ui.add(egui::Slider::new(&mut step.kill_on.threshold, 0.0..=1.0).text("above"));
+                        });
+                    });
                 }
 
                 // if ui.changed() {
@@ -304,6 +510,13 @@ impl eframe::App for Gooey {
                 if self.avi_path.is_some() {
                     if ui.button("taste").clicked() { self.process_video(true); }
                     if ui.button("jar 🥫 avi)").clicked() { self.process_video(false); }
+                    if ui.button("melt 🖼 image").on_hover_text("Pick a still image and move it with this clip's motion").clicked() {
+                        if let Some(image) = rfd::FileDialog::new()
+                            .add_filter("Image", &["png", "jpg", "jpeg", "bmp", "webp", "tif", "tiff"])
+                            .pick_file() {
+                            self.melt_image(image);
+                        }
+                    }
                 }
             });
 
@@ -321,15 +534,15 @@ impl eframe::App for Gooey {
                         let output = rfd::FileDialog::new().save_file();
                         if let Some(output) = output {
                             self.saved_path = Some(output.clone());
-                            self.tx.send(ProcessState::Converting).unwrap();
+                            let _ = self.tx.send(ProcessState::Converting);
                             match ffmpeg_to_mp4(&path, false) {
                                 Ok(mp4_path) => {
                                     self.saved_path = Some(mp4_path);
-                                    self.tx.send(ProcessState::Idle).unwrap();
+                                    let _ = self.tx.send(ProcessState::Idle);
                                 },
                                 Err(e) => {
                                     eprintln!("Error converting datamoshed video to MP4: {:?}", e);
-                                    self.tx.send(ProcessState::Error).unwrap();
+                                    let _ = self.tx.send(ProcessState::Error);
                                 }
                             }
                         }
@@ -1,7 +1,8 @@
 use std::path::PathBuf;
 
 // from ../tomatwo_seed.rs
-use tomatwo_seed::Frame;
+use tomatwo_seed::{AudioGate, Beats, Frame, KillMode, MarkovModel, Opt, Overwrite, Ramp, Signal, Retime, RetimeTarget, Scope};
+use tomatwo_seed::output::DEFAULT_TEMPLATE;
 
 pub enum ProcessState {
     Idle,
@@ -24,6 +25,8 @@ pub struct Gooey {
     pub selected_step: Option<usize>,
     pub original_frame_data: Option<(Vec<Frame>, usize)>,
     pub frame_data_needs_update: bool,
+    pub retime: RetimeSettings,
+    pub output: OutputSettings,
 }
 
 impl Default for Gooey {
@@ -42,6 +45,8 @@ impl Default for Gooey {
             selected_step: None,
             original_frame_data: None,
             frame_data_needs_update: false,
+            retime: RetimeSettings::default(),
+            output: OutputSettings::default(),
         }
     }
 }
@@ -54,6 +59,132 @@ pub struct ProcessingStep {
     pub kill: f32,
     pub kill_rel: f32,
     pub multiply: i32,
+    pub mutate: bool,
+    pub keep_keyframes: usize,
+    pub order: String,
+    pub script: String,
+    pub ramp_kind: String,
+    pub ramp_curve: String,
+    pub ramp_min: f32,
+    pub ramp_max: f32,
+    pub markov: MarkovModel,
+    pub fixed_seed: bool,
+    pub seed: u64,
+    pub beat_sync: bool,
+    pub bpm: f64,
+    pub beat_offset: f64,
+    pub beat_file: Option<PathBuf>,
+    pub beat_list: Option<Beats>,
+    pub kill_on_audio: bool,
+    pub kill_on: AudioGate,
+    pub scoped: bool,
+    pub scope_start: usize,
+    pub scope_end: usize,
+}
+
+impl ProcessingStep {
+    pub fn ramp(&self) -> Ramp {
+        match self.ramp_kind.as_str() {
+            "curve" => format!("curve:{}", self.ramp_curve).parse().unwrap_or(Ramp::Flat),
+            "size" => Ramp::Size { min: self.ramp_min, max: self.ramp_max },
+            "loud" => Ramp::Audio { signal: Signal::Loudness, min: self.ramp_min, max: self.ramp_max },
+            "onset" => Ramp::Audio { signal: Signal::Onset, min: self.ramp_min, max: self.ramp_max },
+            _ => Ramp::Flat,
+        }
+    }
+
+    pub fn beats(&self) -> Option<Beats> {
+        if !self.beat_sync {
+            return None;
+        }
+        Some(self.beat_list.clone().unwrap_or(Beats::Bpm { bpm: self.bpm, offset: self.beat_offset }))
+    }
+
+    pub fn scope(&self) -> Option<Scope> {
+        self.scoped.then_some(Scope { start: self.scope_start, end: Some(self.scope_end.max(self.scope_start)) })
+    }
+
+    /// The library options for this step
+    pub fn to_opt(&self, input: PathBuf) -> Opt {
+        Opt {
+            input,
+            mode: self.mode.clone(),
+            countframes: self.count_frames,
+            positframes: self.posit_frames,
+            kill: self.kill,
+            kill_rel: self.kill_rel * self.kill_rel, // exp slider
+            multiply: self.multiply,
+            kill_mode: if self.mutate { KillMode::Mutate } else { KillMode::Drop },
+            keep_keyframes: self.keep_keyframes,
+            order: Some(self.order.clone()),
+            script: Some(self.script.clone()),
+            ramp: self.ramp(),
+            markov: self.markov,
+            seed: self.fixed_seed.then_some(self.seed),
+            beats: self.beats(),
+            kill_on: self.kill_on_audio.then_some(self.kill_on),
+            scope: self.scope(),
+            ..Default::default()
+        }
+    }
+
+    /// A step with the settings of `opt`, e.g. from a loaded recipe
+    pub fn from_opt(opt: &Opt) -> ProcessingStep {
+        let mut step = ProcessingStep {
+            mode: opt.mode.clone(),
+            count_frames: opt.countframes,
+            posit_frames: opt.positframes,
+            kill: opt.kill,
+            kill_rel: opt.kill_rel.max(0.0).sqrt(),
+            multiply: opt.multiply,
+            mutate: opt.kill_mode == KillMode::Mutate,
+            keep_keyframes: opt.keep_keyframes,
+            order: opt.order.clone().unwrap_or_default(),
+            script: opt.script.clone().unwrap_or_default(),
+            markov: opt.markov,
+            fixed_seed: opt.seed.is_some(),
+            seed: opt.seed.unwrap_or(0),
+            ..Default::default()
+        };
+        match &opt.ramp {
+            Ramp::Flat => {}
+            Ramp::Curve(points) => {
+                step.ramp_kind = "curve".to_string();
+                step.ramp_curve = points.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ");
+            }
+            Ramp::Size { min, max } | Ramp::Audio { min, max, .. } => {
+                step.ramp_kind = match &opt.ramp {
+                    Ramp::Audio { signal: Signal::Loudness, .. } => "loud",
+                    Ramp::Audio { signal: Signal::Onset, .. } => "onset",
+                    _ => "size",
+                }.to_string();
+                step.ramp_min = *min;
+                step.ramp_max = *max;
+            }
+        }
+        match &opt.beats {
+            Some(Beats::Bpm { bpm, offset }) => {
+                step.beat_sync = true;
+                step.bpm = *bpm;
+                step.beat_offset = *offset;
+            }
+            Some(beats @ Beats::List(_)) => {
+                step.beat_sync = true;
+                step.beat_list = Some(beats.clone());
+            }
+            None => {}
+        }
+        if let Some(gate) = opt.kill_on {
+            step.kill_on_audio = true;
+            step.kill_on = gate;
+        }
+        if let Some(scope) = opt.scope {
+            step.scoped = true;
+            step.scope_start = scope.start;
+            step.scope_end = scope.end.unwrap_or(usize::MAX);
+        }
+        step
+    }
 }
 
 impl Default for ProcessingStep {
@@ -65,6 +196,93 @@ impl Default for ProcessingStep {
             kill: 1.0,
             kill_rel: 0.15,
             multiply: 1,
+            mutate: false,
+            keep_keyframes: 0,
+            order: String::new(),
+            script: String::new(),
+            ramp_kind: "flat".to_string(),
+            ramp_curve: "1, 4, 1".to_string(),
+            ramp_min: 0.5,
+            ramp_max: 3.0,
+            markov: MarkovModel::Size,
+            fixed_seed: false,
+            seed: 0,
+            beat_sync: false,
+            bpm: 120.0,
+            beat_offset: 0.0,
+            beat_file: None,
+            beat_list: None,
+            kill_on_audio: false,
+            kill_on: AudioGate { signal: Signal::Onset, threshold: 0.3 },
+            scoped: false,
+            scope_start: 0,
+            scope_end: 100,
         }
     }
-}
\ No newline at end of file
+}
+/// Final retime step, applied after the last processing step
+#[derive(Clone)]
+pub struct RetimeSettings {
+    pub enabled: bool,
+    pub by_duration: bool,
+    pub frames: usize,
+    pub seconds: f64,
+    pub rewrite_fps: bool,
+}
+
+impl Default for RetimeSettings {
+    fn default() -> Self {
+        RetimeSettings {
+            enabled: false,
+            by_duration: true,
+            frames: 300,
+            seconds: 10.0,
+            rewrite_fps: false,
+        }
+    }
+}
+
+impl RetimeSettings {
+    pub fn from_retime(retime: Option<&Retime>) -> RetimeSettings {
+        let mut settings = RetimeSettings::default();
+        if let Some(retime) = retime {
+            settings.enabled = true;
+            settings.rewrite_fps = retime.rewrite_fps;
+            match retime.target {
+                RetimeTarget::Seconds(seconds) => settings.seconds = seconds,
+                RetimeTarget::Frames(frames) => {
+                    settings.by_duration = false;
+                    settings.frames = frames;
+                }
+            }
+        }
+        settings
+    }
+
+    pub fn retime(&self) -> Option<Retime> {
+        if !self.enabled {
+            return None;
+        }
+        Some(if self.by_duration {
+            Retime { target: RetimeTarget::Seconds(self.seconds), rewrite_fps: self.rewrite_fps }
+        } else {
+            Retime { target: RetimeTarget::Frames(self.frames), rewrite_fps: false }
+        })
+    }
+}
+
+/// How the jarred avi is named, see tomatwo_seed's output.rs
+#[derive(Clone)]
+pub struct OutputSettings {
+    pub template: String,
+    pub overwrite: Overwrite,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        OutputSettings {
+            template: DEFAULT_TEMPLATE.to_string(),
+            overwrite: Overwrite::Increment,
+        }
+    }
+}
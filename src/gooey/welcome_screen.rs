@@ -55,10 +55,22 @@ pub fn render_datamosh_guide(ui: &mut egui::Ui) {
         ("random", "randomizes frame order"),
         ("reverse", "reverse frame order"),
         ("invert", "flips each consecutive frame pair"),
-        ("bloom", "duplicates c times p-frame number n"),
-        ("pulse", "duplicates groups of c p-frames every n frames"),
+        ("bloom", "duplicates c times p-frame number n. on the beat: holds each beat's frame for c frames, length stays the same"),
+        ("pulse", "duplicates groups of c p-frames every n frames, or on every beat"),
+        ("markov", "random walk of n frames (clip length if n is 1, at most 100 clip lengths), each step picks one of c frames sized like the real next frame, or near the current one"),
         ("overlap", "copy group of c frames taken from every nth position"),
         ("jiggle", "take frame from around current position. n parameter is spread size [broken]"),
+        ("pingpong", "plays forward then backward c times, over the whole clip or windows of n frames"),
+        ("stutter", "plays each window of n frames c times before moving on"),
+        ("stride", "keeps every nth frame"),
+        ("sort", "sorts frames by size, smallest first"),
+        ("rsort", "sorts frames by size, biggest first"),
+        ("blockshuffle", "shuffles frames within windows of n frames"),
+        ("echo", "re-inserts each p-frame n frames later, c > 1 adds fading echoes at 2n, 3n..."),
+        ("automosh", "drops only the keyframes at scene cuts, holds the first n p-frames after each cut c times. the kill settings don't apply"),
+        ("loop", "seamless loop of the first n frames (whole clip if n is 1) with a c frame transition back into the start"),
+        ("order", "custom order expression: a..b ranges, n*k repeats, rev(..), shuffle(..)"),
+        ("script", "rhai script gets the frame table and returns the new order"),
     ] {
         ui.horizontal(|ui| {
             ui.label(egui::RichText::new(mode).monospace().strong());
@@ -72,6 +84,13 @@ pub fn render_datamosh_guide(ui: &mut egui::Ui) {
     ui.label("• audio (not implemented yet)");
     ui.label("• kill: kill frames with too much data relative to the largest frame. default 0.7");
     ui.label("• kill_rel: kill frames with too much data relative to the previous frame size. default 0.15");
+    ui.label("• mutate: replace keyframes with the previous delta frame instead of killing big frames, keeps timing and audio sync");
+    ui.label("• keep first: with mutate, leave the first N keyframes in place");
+    ui.label("• ramp: vary multiply per frame, along a curve over time, by frame size (small frames held longer) or by the loudness/onsets of PCM audio. fractional rates average out");
+    ui.label("• only kill on: kill only where the PCM audio's onsets or loudness are above a level, e.g. drop keyframes on drum hits");
+    ui.label("• only frames: limit the step to a range of frames, the rest pass through untouched");
+    ui.label("• output name: {stem} {mode} {params} {seed} {date} {n}, and whether to number, overwrite or skip an existing file");
+    ui.label("• save/load recipe: keep all steps in a .toml or .json file, the cli runs it with tomatwo process -r");
 
     ui.add_space(10.0);
     ui.label(egui::RichText::new("Examples:").strong());
@@ -81,6 +100,15 @@ pub fn render_datamosh_guide(ui: &mut egui::Ui) {
         ("Duplicate every 10th frame 5 times each:", "pulse c:5 n:10"),
         ("Shuffle all frames in the video:", "random"),
         ("Copy 4 frames starting from every 2nd frame:", "overlap c:4 n:2"),
+        ("Boomerang loop, 3 times:", "pingpong c:3"),
+        ("Stutter 4 frame windows twice:", "stutter c:2 n:4"),
+        ("Smeared trail, 3 fading echoes 5 frames apart:", "echo c:3 n:5"),
+        ("Loop the first 120 frames with a 15 frame transition:", "loop c:15 n:120"),
+        ("Play, hold, then reverse:", "order 0..120, 120*30, rev(121..200)"),
+        ("Hold a frame for 4 frames on every beat at 128 bpm:", "bloom c:4, on the beat, 128 bpm"),
+        ("Drop keyframes on drum hits (PCM audio avi):", "void: kill_rel ~ 0.15, only kill on onsets above 0.3"),
+        ("Wander through the clip for 300 frames:", "markov c:4 n:300"),
+        ("Move a still image with this clip's motion:", "melt 🖼 image button"),
     ] {
         ui.horizontal(|ui| {
             ui.label(egui::RichText::new(title).strong());
@@ -0,0 +1,381 @@
+// inspect.rs - what's inside an AVI, for looking before moshing
+//
+// `AviReport::read(path)` collects the chunk tree, parsed headers, idx1 stats,
+// keyframe positions and a frame size histogram. Display gives the human
+// readable dump, serde the json one.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use serde::Serialize;
+
+use super::avi::{fourcc_str, AviLayout, Chunk, StreamFormat};
+use super::frames::{is_frame_chunk, Frames};
+
+const HISTOGRAM_BUCKETS: usize = 12;
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIF_MUSTUSEINDEX: u32 = 0x20;
+const AVIF_ISINTERLEAVED: u32 = 0x100;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChunkInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list_type: Option<String>,
+    pub offset: usize,
+    pub size: usize,
+    /// movi holds one chunk per frame, so its children are only counted by id
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub chunk_counts: BTreeMap<String, usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ChunkInfo>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MainHeaderInfo {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    pub micro_sec_per_frame: u32,
+    pub total_frames: u32,
+    pub initial_frames: u32,
+    pub streams: u32,
+    pub max_bytes_per_sec: u32,
+    pub suggested_buffer_size: u32,
+    pub flags: u32,
+    pub flag_names: Vec<&'static str>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamInfo {
+    pub index: usize,
+    /// strh fccType: vids, auds, ...
+    pub kind: String,
+    pub handler: String,
+    pub rate: u32,
+    pub scale: u32,
+    pub fps: f64,
+    pub start: u32,
+    pub length: u32,
+    pub suggested_buffer_size: u32,
+    pub quality: u32,
+    pub sample_size: u32,
+    pub format: FormatInfo,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FormatInfo {
+    Video { codec: String, width: i32, height: i32, bit_count: u16, size_image: u32 },
+    Audio { format_tag: u16, channels: u16, samples_per_sec: u32, avg_bytes_per_sec: u32, block_align: u16, bits_per_sample: u16 },
+    Other { bytes: usize },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IndexStats {
+    pub present: bool,
+    pub entries: usize,
+    pub by_id: BTreeMap<String, usize>,
+    pub keyframe_entries: usize,
+    /// chunks actually in movi, should match `entries`
+    pub movi_chunks: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyframeInfo {
+    pub video_frames: usize,
+    /// video frame numbers
+    pub positions: Vec<usize>,
+    pub mean_interval: Option<f64>,
+    pub max_interval: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HistogramBucket {
+    pub from: usize,
+    pub to: usize,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SizeStats {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FrameInfo {
+    pub index: usize,
+    pub id: String,
+    pub offset: usize,
+    pub size: usize,
+    pub keyframe: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AviReport {
+    pub file: String,
+    pub file_size: usize,
+    pub chunks: ChunkInfo,
+    pub main_header: MainHeaderInfo,
+    pub streams: Vec<StreamInfo>,
+    pub index: IndexStats,
+    pub keyframes: KeyframeInfo,
+    pub video_sizes: Option<SizeStats>,
+    /// every frame in movi, JUNK and ix## left out, only filled in when asked for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<FrameInfo>,
+}
+
+fn chunk_info(chunk: &Chunk, movi_offset: usize) -> ChunkInfo {
+    let mut info = ChunkInfo {
+        id: fourcc_str(&chunk.id),
+        list_type: chunk.list_type.as_ref().map(fourcc_str),
+        offset: chunk.offset,
+        size: chunk.size,
+        chunk_counts: BTreeMap::new(),
+        children: Vec::new(),
+    };
+    if chunk.offset == movi_offset {
+        for child in &chunk.children {
+            *info.chunk_counts.entry(fourcc_str(&child.id)).or_default() += 1;
+        }
+    } else {
+        info.children = chunk.children.iter().map(|c| chunk_info(c, movi_offset)).collect();
+    }
+    info
+}
+
+fn flag_names(flags: u32) -> Vec<&'static str> {
+    [(AVIF_HASINDEX, "HASINDEX"), (AVIF_MUSTUSEINDEX, "MUSTUSEINDEX"), (AVIF_ISINTERLEAVED, "ISINTERLEAVED")]
+        .into_iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+fn stream_info(layout: &AviLayout) -> Vec<StreamInfo> {
+    layout.streams.iter().enumerate().map(|(index, s)| StreamInfo {
+        index,
+        kind: fourcc_str(&s.header.fcc_type),
+        handler: fourcc_str(&s.header.fcc_handler),
+        rate: s.header.rate,
+        scale: s.header.scale,
+        fps: s.header.fps(),
+        start: s.header.start,
+        length: s.header.length,
+        suggested_buffer_size: s.header.suggested_buffer_size,
+        quality: s.header.quality,
+        sample_size: s.header.sample_size,
+        format: match &s.format {
+            StreamFormat::Video(b) => FormatInfo::Video {
+                codec: fourcc_str(&b.compression), width: b.width, height: b.height, bit_count: b.bit_count, size_image: b.size_image,
+            },
+            StreamFormat::Audio(w) => FormatInfo::Audio {
+                format_tag: w.format_tag, channels: w.channels, samples_per_sec: w.samples_per_sec,
+                avg_bytes_per_sec: w.avg_bytes_per_sec, block_align: w.block_align, bits_per_sample: w.bits_per_sample,
+            },
+            StreamFormat::Other(data) => FormatInfo::Other { bytes: data.len() },
+        },
+    }).collect()
+}
+
+fn size_stats(sizes: &[usize]) -> Option<SizeStats> {
+    let min = *sizes.iter().min()?;
+    let max = *sizes.iter().max()?;
+    let width = ((max - min) / HISTOGRAM_BUCKETS + 1).max(1);
+    let mut histogram: Vec<HistogramBucket> = (0..HISTOGRAM_BUCKETS)
+        .map(|b| HistogramBucket { from: min + b * width, to: min + (b + 1) * width - 1, count: 0 })
+        .take_while(|b| b.from <= max)
+        .collect();
+    let last = histogram.len() - 1;
+    for size in sizes {
+        histogram[((size - min) / width).min(last)].count += 1;
+    }
+    Some(SizeStats {
+        min,
+        max,
+        mean: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+        histogram,
+    })
+}
+
+impl AviReport {
+    /// `with_frames` also lists every frame in movi
+    pub fn read(path: &Path, with_frames: bool) -> io::Result<AviReport> {
+        let frames = Frames::open(path)?;
+        let file_size = std::fs::metadata(path)?.len() as usize;
+        Ok(AviReport::from_frames(path.display().to_string(), file_size, &frames, with_frames))
+    }
+
+    fn from_frames(file: String, file_size: usize, frames: &Frames, with_frames: bool) -> AviReport {
+        let layout = frames.layout();
+        let m = &layout.main_header;
+
+        let mut by_id = BTreeMap::new();
+        for entry in &layout.index {
+            *by_id.entry(fourcc_str(&entry.id)).or_default() += 1;
+        }
+
+        let video: Vec<(usize, bool)> = frames.iter().filter(|f| f.is_video()).map(|f| (f.size(), f.is_keyframe())).collect();
+        let positions: Vec<usize> = video.iter().enumerate().filter(|(_, (_, key))| *key).map(|(i, _)| i).collect();
+        let intervals: Vec<usize> = positions.windows(2).map(|w| w[1] - w[0]).collect();
+
+        AviReport {
+            file,
+            file_size,
+            chunks: chunk_info(&layout.riff, layout.movi.offset),
+            main_header: MainHeaderInfo {
+                width: m.width,
+                height: m.height,
+                fps: m.fps(),
+                micro_sec_per_frame: m.micro_sec_per_frame,
+                total_frames: m.total_frames,
+                initial_frames: m.initial_frames,
+                streams: m.streams,
+                max_bytes_per_sec: m.max_bytes_per_sec,
+                suggested_buffer_size: m.suggested_buffer_size,
+                flags: m.flags,
+                flag_names: flag_names(m.flags),
+            },
+            streams: stream_info(layout),
+            index: IndexStats {
+                present: layout.idx1.is_some(),
+                entries: layout.index.len(),
+                by_id,
+                keyframe_entries: layout.index.iter().filter(|e| e.is_keyframe()).count(),
+                movi_chunks: layout.movi.children.len(),
+            },
+            keyframes: KeyframeInfo {
+                video_frames: video.len(),
+                mean_interval: (!intervals.is_empty()).then(|| intervals.iter().sum::<usize>() as f64 / intervals.len() as f64),
+                max_interval: intervals.iter().max().copied(),
+                positions,
+            },
+            video_sizes: size_stats(&video.iter().map(|(size, _)| *size).collect::<Vec<_>>()),
+            frames: if with_frames {
+                // Frames skips the same chunks, so the two line up
+                layout.movi.children.iter().filter(|c| is_frame_chunk(&c.id)).zip(frames.iter()).enumerate().map(|(index, (chunk, frame))| FrameInfo {
+                    index,
+                    id: fourcc_str(&frame.id),
+                    offset: chunk.offset,
+                    size: frame.size(),
+                    keyframe: frame.is_keyframe(),
+                }).collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+fn write_chunk(f: &mut fmt::Formatter<'_>, chunk: &ChunkInfo, depth: usize) -> fmt::Result {
+    let name = match &chunk.list_type {
+        Some(list_type) => format!("{} '{}'", chunk.id, list_type),
+        None => chunk.id.clone(),
+    };
+    let indent = depth * 2 + 2;
+    write!(f, "{:indent$}{:<width$} @{:<10} {:>10} bytes", "", name, chunk.offset, chunk.size, indent = indent, width = 20 - indent.min(18))?;
+    if !chunk.chunk_counts.is_empty() {
+        let counts: Vec<String> = chunk.chunk_counts.iter().map(|(id, n)| format!("{} {}", n, id)).collect();
+        write!(f, "  ({})", counts.join(", "))?;
+    }
+    writeln!(f)?;
+    chunk.children.iter().try_for_each(|c| write_chunk(f, c, depth + 1))
+}
+
+impl fmt::Display for AviReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({} bytes)\n", self.file, self.file_size)?;
+        write_chunk(f, &self.chunks, 0)?;
+
+        let m = &self.main_header;
+        writeln!(f, "\nmain header")?;
+        writeln!(f, "  {}x{} @ {:.3} fps ({} µs/frame), {} frames, {} streams", m.width, m.height, m.fps, m.micro_sec_per_frame, m.total_frames, m.streams)?;
+        writeln!(f, "  flags 0x{:x} [{}], max {} bytes/s, buffer {}", m.flags, m.flag_names.join(" "), m.max_bytes_per_sec, m.suggested_buffer_size)?;
+
+        writeln!(f, "\nstreams")?;
+        for s in &self.streams {
+            let format = match &s.format {
+                FormatInfo::Video { codec, width, height, bit_count, .. } => format!("{} {}x{} {}bpp", codec, width, height, bit_count),
+                FormatInfo::Audio { format_tag, channels, samples_per_sec, bits_per_sample, .. } => {
+                    let name = if *format_tag == 1 { "pcm".to_string() } else { format!("0x{:04x}", format_tag) };
+                    format!("{} {} Hz {}-bit {}ch", name, samples_per_sec, bits_per_sample, channels)
+                }
+                FormatInfo::Other { bytes } => format!("{} bytes of format data", bytes),
+            };
+            writeln!(f, "  {} {} '{}' {}, {}/{} = {:.3}/s, length {}", s.index, s.kind, s.handler, format, s.rate, s.scale, s.fps, s.length)?;
+        }
+
+        let i = &self.index;
+        writeln!(f, "\nidx1")?;
+        if i.present {
+            let ids: Vec<String> = i.by_id.iter().map(|(id, n)| format!("{} {}", n, id)).collect();
+            writeln!(f, "  {} entries ({}), {} flagged keyframe", i.entries, ids.join(", "), i.keyframe_entries)?;
+            if i.entries != i.movi_chunks {
+                writeln!(f, "  ! {} entries but {} chunks in movi", i.entries, i.movi_chunks)?;
+            }
+        } else {
+            writeln!(f, "  none, keyframes are guessed")?;
+        }
+
+        let k = &self.keyframes;
+        writeln!(f, "\nkeyframes")?;
+        write!(f, "  {} of {} video frames", k.positions.len(), k.video_frames)?;
+        if let (Some(mean), Some(max)) = (k.mean_interval, k.max_interval) {
+            write!(f, ", every {:.1} frames on average (longest gap {})", mean, max)?;
+        }
+        writeln!(f)?;
+        let shown: Vec<String> = k.positions.iter().take(64).map(|p| p.to_string()).collect();
+        writeln!(f, "  at {}{}", shown.join(" "), if k.positions.len() > 64 { " ..." } else { "" })?;
+
+        if let Some(sizes) = &self.video_sizes {
+            writeln!(f, "\nvideo frame sizes: min {}, mean {:.0}, max {}", sizes.min, sizes.mean, sizes.max)?;
+            let most = sizes.histogram.iter().map(|b| b.count).max().unwrap_or(1).max(1);
+            for b in &sizes.histogram {
+                writeln!(f, "  {:>9} - {:<9} {:<40} {}", b.from, b.to, "#".repeat((b.count * 40).div_ceil(most)), b.count)?;
+            }
+        }
+
+        if !self.frames.is_empty() {
+            writeln!(f, "\n  #        id    offset        size  flags")?;
+            for fr in &self.frames {
+                writeln!(f, "  {:<8} {}  {:<10} {:>8}  {}", fr.index, fr.id, fr.offset, fr.size, if fr.keyframe { "key" } else { "" })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::tests::{chunk, list};
+
+    #[test]
+    fn frame_offsets_skip_junk_in_movi() {
+        let avi = list(b"RIFF", b"AVI ", &[
+            list(b"LIST", b"hdrl", &[chunk(b"avih", &[0; 56])]),
+            list(b"LIST", b"movi", &[
+                chunk(b"00dc", &[1; 10]),
+                chunk(b"JUNK", &[0; 6]),
+                chunk(b"00dc", &[2; 4]),
+                chunk(b"ix00", &[0; 8]),
+                chunk(b"00dc", &[3; 2]),
+            ]),
+            chunk(b"idx1", &[]),
+        ]);
+        let frames = Frames::from_bytes(avi).unwrap();
+        let report = AviReport::from_frames("test.avi".to_string(), 0, &frames, true);
+
+        // movi's data starts at 100: RIFF header 12, hdrl list 76, movi list header 12
+        let offsets: Vec<usize> = report.frames.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, vec![100, 132, 160]);
+        let sizes: Vec<usize> = report.frames.iter().map(|f| f.size).collect();
+        assert_eq!(sizes, vec![10, 4, 2]);
+        assert!(report.frames.iter().all(|f| f.id == "00dc"));
+    }
+}
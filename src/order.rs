@@ -0,0 +1,361 @@
+// order.rs - frame order expressions
+//
+// A small language for custom orderings over the cleaned frame list, e.g.
+//
+//     0..120, 120*30, rev(121..200), shuffle(200..260)
+//
+//   n          frame n
+//   a..b       frames a up to (not including) b, `a..` runs to the end
+//   a..b:s     every s-th frame from a to b
+//   x*k        x repeated k times, x can be any term
+//   rev(...)   reversed
+//   shuffle(...)  shuffled
+//   (...)      grouping
+//
+// Ranges are clipped to the clip length and single frames past the end are skipped.
+// Repeats can't add up to more than MAX_REPEAT copies of a frame, brackets can't
+// nest deeper than MAX_DEPTH, and the order can be at most MAX_GROWTH times as long
+// as the clip (or MAX_REPEAT frames, if that's more), so a typo can't eat all the memory.
+
+use std::fmt;
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+/// Most copies repeats can make of one frame, nested repeats multiply
+const MAX_REPEAT: usize = 10_000;
+/// Deepest nesting of brackets and functions
+const MAX_DEPTH: usize = 32;
+/// Most frames the order can have, per frame in the clip
+const MAX_GROWTH: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderError {
+    /// byte offset into the expression
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl OrderError {
+    fn new(pos: usize, msg: impl Into<String>) -> Self {
+        OrderError { pos, msg: msg.into() }
+    }
+
+    /// The expression with a caret under the error position
+    pub fn pretty(&self, src: &str) -> String {
+        format!("{}\n{}^ {}", src, " ".repeat(src[..self.pos.min(src.len())].chars().count()), self.msg)
+    }
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "order expression error at position {}: {}", self.pos, self.msg)
+    }
+}
+
+impl std::error::Error for OrderError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Term {
+    Frame(usize),
+    Range { start: usize, end: Option<usize>, step: usize },
+    Repeat(Box<Term>, usize),
+    Rev(Vec<Term>),
+    Shuffle(Vec<Term>),
+    Group(Vec<Term>),
+}
+
+impl Term {
+    /// Most copies of one frame this term makes through repeats
+    fn repeats(&self) -> usize {
+        match self {
+            Term::Frame(_) | Term::Range { .. } => 1,
+            Term::Repeat(inner, count) => inner.repeats().saturating_mul(*count),
+            Term::Rev(terms) | Term::Shuffle(terms) | Term::Group(terms) => {
+                terms.iter().map(Term::repeats).max().unwrap_or(1)
+            }
+        }
+    }
+}
+
+/// A compiled order expression
+#[derive(Clone, Debug, PartialEq)]
+pub struct Order {
+    terms: Vec<Term>,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.src[self.pos..].chars().next() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.src[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.src[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), OrderError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(OrderError::new(self.pos, format!("expected '{}'", token)))
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, OrderError> {
+        self.skip_ws();
+        let start = self.pos;
+        let len = self.src[start..].chars().take_while(|c| c.is_ascii_digit()).count();
+        if len == 0 {
+            return Err(OrderError::new(start, "expected a frame number"));
+        }
+        self.pos += len;
+        self.src[start..self.pos].parse().map_err(|_| OrderError::new(start, "number too large"))
+    }
+
+    fn list(&mut self) -> Result<Vec<Term>, OrderError> {
+        let mut terms = vec![self.item()?];
+        while self.eat(",") {
+            terms.push(self.item()?);
+        }
+        Ok(terms)
+    }
+
+    fn item(&mut self) -> Result<Term, OrderError> {
+        let mut term = self.term()?;
+        while self.eat("*") {
+            self.skip_ws();
+            let at = self.pos;
+            let count = self.number()?;
+            if count.saturating_mul(term.repeats()) > MAX_REPEAT {
+                return Err(OrderError::new(at, format!("repeats add up to more than {}", MAX_REPEAT)));
+            }
+            term = Term::Repeat(Box::new(term), count);
+        }
+        Ok(term)
+    }
+
+    /// The list inside brackets, after the opening one
+    fn nested(&mut self) -> Result<Vec<Term>, OrderError> {
+        if self.depth == MAX_DEPTH {
+            return Err(OrderError::new(self.pos - 1, format!("nested more than {} deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let terms = self.list()?;
+        self.depth -= 1;
+        self.expect(")")?;
+        Ok(terms)
+    }
+
+    fn term(&mut self) -> Result<Term, OrderError> {
+        self.skip_ws();
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let first = self.number()?;
+                if !self.eat("..") {
+                    return Ok(Term::Frame(first));
+                }
+                let end = match self.peek() {
+                    Some(c) if c.is_ascii_digit() => Some(self.number()?),
+                    _ => None,
+                };
+                let step = if self.eat(":") {
+                    let at = self.pos;
+                    match self.number()? {
+                        0 => return Err(OrderError::new(at, "step must be at least 1")),
+                        step => step,
+                    }
+                } else {
+                    1
+                };
+                Ok(Term::Range { start: first, end, step })
+            }
+            Some('(') => {
+                self.eat("(");
+                Ok(Term::Group(self.nested()?))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let len = self.src[self.pos..].chars().take_while(|c| c.is_ascii_alphanumeric() || *c == '_').count();
+                let name = &self.src[self.pos..self.pos + len];
+                self.pos += len;
+                let wrap: fn(Vec<Term>) -> Term = match name {
+                    "rev" | "reverse" => Term::Rev,
+                    "shuffle" | "random" => Term::Shuffle,
+                    _ => return Err(OrderError::new(start, format!("unknown function '{}'", name))),
+                };
+                self.expect("(")?;
+                Ok(wrap(self.nested()?))
+            }
+            Some(c) => Err(OrderError::new(self.pos, format!("unexpected '{}'", c))),
+            None => Err(OrderError::new(self.pos, "unexpected end of expression")),
+        }
+    }
+}
+
+impl Order {
+    pub fn parse(src: &str) -> Result<Order, OrderError> {
+        let mut parser = Parser { src, pos: 0, depth: 0 };
+        let terms = parser.list()?;
+        if parser.peek().is_some() {
+            return Err(OrderError::new(parser.pos, "expected ',' or end of expression"));
+        }
+        Ok(Order { terms })
+    }
+
+    /// Frame indices for a clip of `len` frames, or an error if there would be too many
+    pub fn eval<R: Rng>(&self, len: usize, rng: &mut R) -> Result<Vec<usize>, OrderError> {
+        let max_len = len.saturating_mul(MAX_GROWTH).max(MAX_REPEAT);
+        let mut out = Vec::new();
+        for term in &self.terms {
+            eval_term(term, len, max_len, rng, &mut out)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Append `term`'s frames to `out`, as long as it stays within `max_len`
+fn eval_term<R: Rng>(term: &Term, len: usize, max_len: usize, rng: &mut R, out: &mut Vec<usize>) -> Result<(), OrderError> {
+    let too_long = || OrderError::new(0, format!("the order is more than {} frames, {} times the clip", max_len, MAX_GROWTH));
+    match term {
+        Term::Frame(i) => {
+            if *i < len {
+                out.push(*i);
+            }
+        }
+        Term::Range { start, end, step } => {
+            let end = end.unwrap_or(len).min(len);
+            out.extend((*start..end).step_by(*step));
+        }
+        Term::Repeat(inner, count) => {
+            let mut once = Vec::new();
+            eval_term(inner, len, max_len, rng, &mut once)?;
+            if once.len().saturating_mul(*count) > max_len - out.len().min(max_len) {
+                return Err(too_long());
+            }
+            for _ in 0..*count {
+                out.extend_from_slice(&once);
+            }
+        }
+        Term::Rev(terms) | Term::Shuffle(terms) | Term::Group(terms) => {
+            let mut inner = Vec::new();
+            for t in terms {
+                eval_term(t, len, max_len, rng, &mut inner)?;
+            }
+            match term {
+                Term::Rev(_) => inner.reverse(),
+                Term::Shuffle(_) => inner.shuffle(rng),
+                _ => {}
+            }
+            out.extend(inner);
+        }
+    }
+    if out.len() > max_len {
+        return Err(too_long());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn eval(src: &str, len: usize) -> Vec<usize> {
+        Order::parse(src).unwrap().eval(len, &mut StdRng::seed_from_u64(7)).unwrap()
+    }
+
+    fn error_at(src: &str) -> (usize, String) {
+        let err = Order::parse(src).unwrap_err();
+        (err.pos, err.msg)
+    }
+
+    #[test]
+    fn frames_and_ranges() {
+        assert_eq!(eval("3, 0..2, 5..", 8), vec![3, 0, 1, 5, 6, 7]);
+        assert_eq!(eval("0..10:4", 8), vec![0, 4]);
+        assert_eq!(eval("9, 2", 5), vec![2]);
+    }
+
+    #[test]
+    fn rev_and_shuffle() {
+        assert_eq!(eval("rev(0..4)", 10), vec![3, 2, 1, 0]);
+        assert_eq!(eval("reverse(1, 5..7)", 10), vec![6, 5, 1]);
+        let mut shuffled = eval("shuffle(0..20)", 20);
+        assert_ne!(shuffled, (0..20).collect::<Vec<_>>());
+        shuffled.sort();
+        assert_eq!(shuffled, (0..20).collect::<Vec<_>>());
+        assert_eq!(eval("shuffle(0..20)", 20), eval("random(0..20)", 20));
+    }
+
+    #[test]
+    fn repeats() {
+        assert_eq!(eval("2*3", 5), vec![2, 2, 2]);
+        assert_eq!(eval("0..2*2", 5), vec![0, 1, 0, 1]);
+        assert_eq!(eval("1*2*2", 5), vec![1; 4]);
+        assert_eq!(eval("rev(0..2)*2", 5), vec![1, 0, 1, 0]);
+        assert_eq!(eval("4*0", 5), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn nesting() {
+        assert_eq!(eval("(0, (1, 2)*2), rev((3, 4), 0..1)", 5), vec![0, 1, 2, 1, 2, 0, 4, 3]);
+        let deep = format!("{}0{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert_eq!(eval(&deep, 5), vec![0]);
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error_at("0..5:0"), (5, "step must be at least 1".to_string()));
+        assert_eq!(error_at("1, spin(2)"), (3, "unknown function 'spin'".to_string()));
+        assert_eq!(error_at("rev(1, 2"), (8, "expected ')'".to_string()));
+        assert_eq!(error_at("1 2"), (2, "expected ',' or end of expression".to_string()));
+        assert_eq!(error_at("1, "), (3, "unexpected end of expression".to_string()));
+        assert_eq!(error_at("1*x").0, 2);
+        assert_eq!(error_at("0..3, #").0, 6);
+    }
+
+    #[test]
+    fn caps() {
+        assert!(Order::parse("0*10000").is_ok());
+        assert_eq!(error_at("0*10001").0, 2);
+        assert_eq!(error_at("(0*100, 1)*101").0, 11);
+        assert_eq!(error_at("0*100*101").0, 6);
+        let deep = format!("{}0{}", "(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1));
+        assert_eq!(error_at(&deep).0, MAX_DEPTH);
+    }
+
+    #[test]
+    fn evaluated_length_is_capped() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let order = Order::parse("0..*10000").unwrap();
+        assert!(order.eval(100_000, &mut rng).unwrap_err().msg.contains("more than 10000000 frames"));
+        assert_eq!(order.eval(1, &mut rng).unwrap().len(), 10_000);
+
+        // 100 frames make room for 10000, nested terms count towards the whole
+        assert_eq!(Order::parse("(0..*50, 0..*50)").unwrap().eval(100, &mut rng).unwrap().len(), 10_000);
+        assert!(Order::parse("(0..*50, 0..*50), 0").unwrap().eval(100, &mut rng).is_err());
+        assert!(Order::parse("0..*100, 0..*1").unwrap().eval(100, &mut rng).is_err());
+    }
+}
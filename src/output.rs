@@ -0,0 +1,210 @@
+// output.rs - where outputs go: name templates, what to do when the file
+// is already there, and writing through a temp file so it never ends up half written
+//
+// Templates fill in:
+//   {stem}    input file name without extension
+//   {mode}    the steps' modes, "bloom-pulse"
+//   {params}  the steps' modes with c and n, "bloom-c30-n100-pulse-c5"
+//   {seed}    the first step's seed, picked at random when it wasn't given
+//   {date}    UTC time of the run, 2024-08-11-153000
+//   {n}       1, or the first free number with `Overwrite::Increment`
+// and get .avi added unless they end in it. Increment claims the free name by
+// creating it empty, so parallel runs can't pick the same one.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_TEMPLATE: &str = "{stem}-{params}";
+
+/// What to do when the output file already exists
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overwrite {
+    /// write over it
+    #[default]
+    Replace,
+    /// leave it and don't process
+    Skip,
+    /// use the first free {n}, or add -2, -3, .. to the name
+    Increment,
+}
+
+impl std::str::FromStr for Overwrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overwrite, String> {
+        match s.trim() {
+            "overwrite" | "replace" => Ok(Overwrite::Replace),
+            "skip" => Ok(Overwrite::Skip),
+            "increment" | "number" => Ok(Overwrite::Increment),
+            other => Err(format!("unknown overwrite policy '{}', use overwrite, skip or increment", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Overwrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Overwrite::Replace => write!(f, "overwrite"),
+            Overwrite::Skip => write!(f, "skip"),
+            Overwrite::Increment => write!(f, "increment"),
+        }
+    }
+}
+
+/// The values a template can use
+pub struct NameParts {
+    pub stem: String,
+    pub mode: String,
+    pub params: String,
+    pub seed: Option<u64>,
+    pub date: String,
+}
+
+/// Where to write, or the existing file that `Overwrite::Skip` keeps
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputTarget {
+    Write(PathBuf),
+    Exists(PathBuf),
+}
+
+/// `-`, the path that means stdout
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// `template` filled in, with .avi added if it doesn't end in it
+pub fn file_name(template: &str, parts: &NameParts, n: usize) -> String {
+    let mut name = template
+        .replace("{stem}", &parts.stem)
+        .replace("{mode}", &parts.mode)
+        .replace("{params}", &parts.params)
+        .replace("{seed}", &parts.seed.map_or("random".to_string(), |s| s.to_string()))
+        .replace("{date}", &parts.date)
+        .replace("{n}", &n.to_string());
+    if !template.contains("{n}") && n > 1 {
+        name += &format!("-{}", n);
+    }
+    if !name.to_ascii_lowercase().ends_with(".avi") {
+        name += ".avi";
+    }
+    name
+}
+
+/// `path` with -n added to the file name, past the first
+pub fn numbered(path: &Path, n: usize) -> PathBuf {
+    if n <= 1 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}-{}.{}", stem, n, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}-{}", stem, n)),
+    }
+}
+
+/// Apply `policy` to the candidate paths `path(1)`, `path(2)`, ..
+pub fn pick<F: Fn(usize) -> PathBuf>(policy: Overwrite, path: F) -> io::Result<OutputTarget> {
+    let first = path(1);
+    match policy {
+        Overwrite::Replace => Ok(OutputTarget::Write(first)),
+        Overwrite::Skip if first.exists() => Ok(OutputTarget::Exists(first)),
+        Overwrite::Skip => Ok(OutputTarget::Write(first)),
+        Overwrite::Increment => {
+            if let Some(dir) = first.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            for candidate in (1..).map(path) {
+                match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+                    Ok(_) => return Ok(OutputTarget::Write(candidate)),
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            unreachable!("ran out of numbers")
+        }
+    }
+}
+
+/// Removes the empty file `pick` claimed if it's dropped before the output is written over it
+pub struct Claim(Option<PathBuf>);
+
+impl Claim {
+    pub fn new(path: Option<PathBuf>) -> Claim {
+        Claim(path)
+    }
+
+    /// The output is written, leave it
+    pub fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Days since 1970-01-01 as (year, month, day), from Howard Hinnant's civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// The current UTC time as 2024-08-11-153000
+pub fn date_stamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02}-{:02}{:02}{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Write `path` by having `write` fill a temp file next to it and renaming that into place,
+/// so a failed or interrupted write leaves the old file (or nothing) instead of half an AVI
+pub fn write_atomic<F: FnOnce(&Path) -> io::Result<()>>(path: &Path, write: F) -> io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let temp = dir.join(format!(".{}.{}-{}.part",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)));
+    let result = write(&temp).and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increment_claims_each_name_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("out").join("clip.avi");
+        let picks: Vec<OutputTarget> = (0..3).map(|_| pick(Overwrite::Increment, |n| numbered(&base, n)).unwrap()).collect();
+        assert_eq!(picks, [1, 2, 3].map(|n| OutputTarget::Write(numbered(&base, n))));
+        assert!(numbered(&base, 3).exists());
+
+        Claim::new(Some(numbered(&base, 3))).keep();
+        assert!(numbered(&base, 3).exists());
+        drop(Claim::new(Some(numbered(&base, 3))));
+        assert!(!numbered(&base, 3).exists());
+    }
+}
@@ -0,0 +1,438 @@
+// recipe.rs - moshing recipes shared by the cli and the gui
+//
+// A recipe is the ordered list of steps plus the settings that apply to all
+// of them, saved as TOML (or JSON, by file extension):
+//
+//   version = 1
+//   audio = false
+//
+//   [[steps]]
+//   mode = "void"
+//   kill = 0.7
+//
+//   [[steps]]
+//   mode = "bloom"
+//   c = 30
+//   n = 100
+//   scope = "0..400"
+//
+//   [retime]
+//   seconds = 10.0
+//
+// Everything but `mode` has a default. Unknown keys are kept and reported as
+// warnings, so recipes from newer versions still load.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use super::{AudioGate, Beats, KillMode, MarkovModel, Opt, Order, Ramp, Retime, RetimeTarget, Scope};
+use super::script::check_script;
+
+pub const RECIPE_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecipeFormat {
+    Toml,
+    Json,
+}
+
+impl RecipeFormat {
+    pub fn from_path(path: &Path) -> RecipeFormat {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("json") => RecipeFormat::Json,
+            _ => RecipeFormat::Toml,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Recipe {
+    /// format version the recipe was written with, 0 when missing
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// keep audio chunks in the frame table for every step
+    #[serde(default)]
+    pub audio: bool,
+    #[serde(default)]
+    pub steps: Vec<RecipeStep>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retime: Option<RecipeRetime>,
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RecipeRetime {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<f64>,
+    #[serde(default)]
+    pub rewrite_fps: bool,
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<String, serde_json::Value>,
+}
+
+fn default_mode() -> String { "void".to_string() }
+fn default_one() -> usize { 1 }
+fn default_kill() -> f64 { 0.7 }
+fn default_kill_rel() -> f64 { 0.15 }
+fn default_multiply() -> i32 { 1 }
+fn default_ramp() -> String { "flat".to_string() }
+fn default_markov() -> String { "size".to_string() }
+
+/// One step, with the same names as the cli options
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecipeStep {
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    #[serde(default = "default_one", alias = "countframes")]
+    pub c: usize,
+    #[serde(default = "default_one", alias = "positframes")]
+    pub n: usize,
+    #[serde(default)]
+    pub firstframe: bool,
+    #[serde(default = "default_kill")]
+    pub kill: f64,
+    #[serde(default = "default_kill_rel")]
+    pub kill_rel: f64,
+    #[serde(default = "default_multiply")]
+    pub multiply: i32,
+    #[serde(default)]
+    pub mutate: bool,
+    #[serde(default)]
+    pub keep_keyframes: usize,
+    /// e.g. "onset:0.3"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill_on: Option<String>,
+    /// e.g. "curve:1,4,1", see `Ramp`
+    #[serde(default = "default_ramp")]
+    pub ramp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<String>,
+    /// rhai source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    #[serde(default = "default_markov")]
+    pub markov: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f64>,
+    #[serde(default)]
+    pub beat_offset: f64,
+    /// beat list file, relative to the recipe
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beats: Option<PathBuf>,
+    /// beat times in seconds, written inline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beat_times: Option<Vec<f64>>,
+    /// e.g. "120..300"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<String, serde_json::Value>,
+}
+
+/// `x` as the f64 it reads as, so 0.7f32 is saved as 0.7 and not 0.699999988079071
+fn shortest_f64(x: f32) -> f64 {
+    x.to_string().parse().unwrap_or(x as f64)
+}
+
+impl RecipeStep {
+    pub fn from_opt(opt: &Opt) -> RecipeStep {
+        let (bpm, beat_offset, beat_times) = match &opt.beats {
+            Some(Beats::Bpm { bpm, offset }) => (Some(*bpm), *offset, None),
+            Some(Beats::List(times)) => (None, 0.0, Some(times.clone())),
+            None => (None, 0.0, None),
+        };
+        RecipeStep {
+            mode: opt.mode.clone(),
+            c: opt.countframes,
+            n: opt.positframes,
+            firstframe: opt.firstframe,
+            kill: shortest_f64(opt.kill),
+            kill_rel: shortest_f64(opt.kill_rel),
+            multiply: opt.multiply,
+            mutate: opt.kill_mode == KillMode::Mutate,
+            keep_keyframes: opt.keep_keyframes,
+            kill_on: opt.kill_on.map(|g| g.to_string()),
+            ramp: opt.ramp.to_string(),
+            // only worth saving for the modes that read them
+            order: opt.order.clone().filter(|o| opt.mode == "order" && !o.is_empty()),
+            script: opt.script.clone().filter(|s| opt.mode == "script" && !s.is_empty()),
+            markov: opt.markov.to_string(),
+            seed: opt.seed,
+            bpm,
+            beat_offset,
+            beats: None,
+            beat_times,
+            scope: opt.scope.map(|s| s.to_string()),
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    /// Validated options for this step. `base_dir` is where relative paths start
+    pub fn to_opt(&self, input: &Path, base_dir: &Path) -> Result<Opt, String> {
+        if let Some(order) = &self.order {
+            Order::parse(order).map_err(|e| format!("{}\n{}", e, e.pretty(order)))?;
+        }
+        if let Some(script) = &self.script {
+            check_script(script)?;
+        }
+        let beats = match (self.bpm, &self.beat_times, &self.beats) {
+            (Some(bpm), _, _) => Some(Beats::Bpm { bpm, offset: self.beat_offset }),
+            (None, Some(times), _) => Some(Beats::List(times.clone())),
+            (None, None, Some(path)) => Some(Beats::read_list(&base_dir.join(path)).map_err(|e| e.to_string())?),
+            (None, None, None) => None,
+        };
+        Ok(Opt {
+            input: input.to_path_buf(),
+            mode: self.mode.clone(),
+            countframes: self.c,
+            positframes: self.n,
+            firstframe: self.firstframe,
+            kill: self.kill as f32,
+            kill_rel: self.kill_rel as f32,
+            multiply: self.multiply,
+            kill_mode: if self.mutate { KillMode::Mutate } else { KillMode::Drop },
+            keep_keyframes: self.keep_keyframes,
+            kill_on: self.kill_on.as_deref().map(str::parse::<AudioGate>).transpose()?,
+            ramp: self.ramp.parse::<Ramp>()?,
+            order: self.order.clone(),
+            script: self.script.clone(),
+            markov: self.markov.parse::<MarkovModel>()?,
+            seed: self.seed,
+            beats,
+            scope: self.scope.as_deref().map(str::parse::<Scope>).transpose()?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Split `s` on whitespace, keeping quoted parts together: `order="0..10, 20*5"`
+fn split_words(s: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut in_word = false;
+    for ch in s.chars() {
+        match (quote, ch) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(ch);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unclosed quote in '{}'", s));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// A step written on one line the way the cli's `--step` takes it:
+/// `mode=bloom c=30 n=100 kill=0.5`, with the same keys as a recipe step
+impl std::str::FromStr for RecipeStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RecipeStep, String> {
+        let pairs = split_words(s)?.into_iter().map(|word| match word.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(format!("'{}' should look like key=value", word)),
+        }).collect::<Result<Vec<_>, String>>()?;
+        RecipeStep::from_pairs(&pairs)
+    }
+}
+
+/// Step keys whose values are strings even when they look like a number, `order=5`
+const STRING_KEYS: [&str; 8] = ["mode", "order", "script", "scope", "ramp", "markov", "kill_on", "beats"];
+
+impl RecipeStep {
+    /// A step from `key=value` pairs, see `--step`
+    pub fn from_pairs(pairs: &[(String, String)]) -> Result<RecipeStep, String> {
+        let mut table = serde_json::Map::new();
+        for (key, value) in pairs {
+            let key = key.trim().replace('-', "_");
+            // numbers, bools and [lists] keep their type, anything else is a string
+            let value = match serde_json::from_str::<serde_json::Value>(value) {
+                Ok(v) if !v.is_string() && !STRING_KEYS.contains(&key.as_str()) => v,
+                _ => serde_json::Value::String(value.to_string()),
+            };
+            table.insert(key, value);
+        }
+        if !table.contains_key("mode") {
+            return Err("a step needs a mode=".to_string());
+        }
+        let step: RecipeStep = serde_json::from_value(serde_json::Value::Object(table))
+            .map_err(|e| e.to_string())?;
+        if let Some(key) = step.unknown.keys().next() {
+            return Err(format!("unknown key '{}'", key));
+        }
+        Ok(step)
+    }
+}
+
+impl RecipeRetime {
+    pub fn from_retime(retime: &Retime) -> RecipeRetime {
+        let (frames, seconds) = match retime.target {
+            RetimeTarget::Frames(frames) => (Some(frames), None),
+            RetimeTarget::Seconds(seconds) => (None, Some(seconds)),
+        };
+        RecipeRetime { frames, seconds, rewrite_fps: retime.rewrite_fps, unknown: BTreeMap::new() }
+    }
+
+    pub fn to_retime(&self) -> Result<Retime, String> {
+        let target = match (self.frames, self.seconds) {
+            (Some(frames), None) => RetimeTarget::Frames(frames),
+            (None, Some(seconds)) => RetimeTarget::Seconds(seconds),
+            _ => return Err("retime needs either frames or seconds".to_string()),
+        };
+        Ok(Retime { target, rewrite_fps: self.rewrite_fps })
+    }
+}
+
+fn unknown_keys(warnings: &mut Vec<String>, section: &str, unknown: &BTreeMap<String, serde_json::Value>) {
+    for key in unknown.keys() {
+        warnings.push(format!("unknown key '{}' in {}, ignored", key, section));
+    }
+}
+
+impl Recipe {
+    /// Recipe for running `steps` in order. Retime and audio are taken from the steps
+    pub fn from_steps(steps: &[Opt]) -> Recipe {
+        Recipe {
+            version: RECIPE_VERSION,
+            name: None,
+            audio: steps.first().is_some_and(|s| s.audio),
+            steps: steps.iter().map(RecipeStep::from_opt).collect(),
+            retime: steps.last().and_then(|s| s.retime.as_ref()).map(RecipeRetime::from_retime),
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    /// Options for every step, ready for `process_steps`
+    pub fn to_steps(&self, input: &Path, base_dir: &Path) -> Result<Vec<Opt>, String> {
+        if self.steps.is_empty() {
+            return Err("recipe has no steps".to_string());
+        }
+        let mut steps = self.steps.iter().enumerate()
+            .map(|(i, step)| step.to_opt(input, base_dir).map_err(|e| format!("step {}: {}", i + 1, e)))
+            .collect::<Result<Vec<Opt>, String>>()?;
+        for step in steps.iter_mut() {
+            step.audio = self.audio;
+        }
+        if let (Some(retime), Some(last)) = (&self.retime, steps.last_mut()) {
+            last.retime = Some(retime.to_retime()?);
+        }
+        Ok(steps)
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.version == 0 {
+            warnings.push(format!("recipe has no version, reading it as version {}", RECIPE_VERSION));
+        } else if self.version > RECIPE_VERSION {
+            warnings.push(format!("recipe is version {}, this tomatwo reads up to {}, some settings may be ignored", self.version, RECIPE_VERSION));
+        }
+        unknown_keys(&mut warnings, "recipe", &self.unknown);
+        if let Some(retime) = &self.retime {
+            unknown_keys(&mut warnings, "retime", &retime.unknown);
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            unknown_keys(&mut warnings, &format!("step {}", i + 1), &step.unknown);
+        }
+        warnings
+    }
+
+    pub fn parse(text: &str, format: RecipeFormat) -> Result<Recipe, String> {
+        match format {
+            RecipeFormat::Toml => toml::from_str(text).map_err(|e| format!("Bad recipe toml: {}", e)),
+            RecipeFormat::Json => serde_json::from_str(text).map_err(|e| format!("Bad recipe json: {}", e)),
+        }
+    }
+
+    pub fn format(&self, format: RecipeFormat) -> Result<String, String> {
+        match format {
+            RecipeFormat::Toml => toml::to_string_pretty(self).map_err(|e| e.to_string()),
+            RecipeFormat::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Load a recipe, printing any warnings about it
+    pub fn read(path: &Path) -> io::Result<Recipe> {
+        let recipe = Recipe::parse(&fs::read_to_string(path)?, RecipeFormat::from_path(path))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for warning in recipe.warnings() {
+            eprintln!("> Warning: {:?}: {}", path, warning);
+        }
+        Ok(recipe)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let text = self.format(RecipeFormat::from_path(path))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_numbers_and_bools_keep_their_type() {
+        let step: RecipeStep = "mode=bloom c=30 n=100 kill=0.5 mutate=true beat_times=[1.5,3]".parse().unwrap();
+        assert_eq!(step.mode, "bloom");
+        assert_eq!((step.c, step.n), (30, 100));
+        assert_eq!(step.kill, 0.5);
+        assert!(step.mutate);
+        assert_eq!(step.beat_times, Some(vec![1.5, 3.0]));
+    }
+
+    #[test]
+    fn step_string_keys_stay_strings() {
+        let step: RecipeStep = "mode=order order=5".parse().unwrap();
+        assert_eq!(step.order.as_deref(), Some("5"));
+        let step: RecipeStep = "mode=void scope=10.. kill_on=onset:0.3 ramp=flat markov=size".parse().unwrap();
+        assert_eq!(step.scope.as_deref(), Some("10.."));
+        assert_eq!(step.kill_on.as_deref(), Some("onset:0.3"));
+        let step = RecipeStep::from_pairs(&[("mode".to_string(), "true".to_string())]).unwrap();
+        assert_eq!(step.mode, "true");
+    }
+
+    #[test]
+    fn step_quotes_and_dashes() {
+        let step: RecipeStep = r#"mode=order order="rev(0..10), 5*3" kill-rel=0.2"#.parse().unwrap();
+        assert_eq!(step.order.as_deref(), Some("rev(0..10), 5*3"));
+        assert_eq!(step.kill_rel, 0.2);
+    }
+
+    #[test]
+    fn step_errors() {
+        assert!("c=3".parse::<RecipeStep>().unwrap_err().contains("mode"));
+        assert!("mode=void frobnicate=1".parse::<RecipeStep>().unwrap_err().contains("frobnicate"));
+        assert!("mode=void c".parse::<RecipeStep>().unwrap_err().contains("key=value"));
+        assert!("mode=order order=\"0..3".parse::<RecipeStep>().unwrap_err().contains("unclosed"));
+        assert!("mode=void c=lots".parse::<RecipeStep>().is_err());
+    }
+}
@@ -0,0 +1,195 @@
+// script.rs - custom effects written in rhai
+//
+// The script sees the cleaned frame list and returns the new order:
+//
+//   frames   array of #{ index, size, type, keyframe, time, loudness, onset }
+//            (time counts the video frames before it in `frames`, audio chunks
+//            get the time of the video frame before them; loudness and onset
+//            are 0..1, or 0 without PCM audio in the source)
+//   len      number of frames
+//   c, n     the step's count/position params
+//   fps      source frame rate
+//   rand()   float in 0..1, rand(a, b) int in a..b, from the step's seed
+//
+// and evaluates to an array whose items are either a position in `frames`
+// or #{ frame: position, repeat: k } with k up to 10000. The whole order can be
+// at most 100 times as long as `frames` (or 10000 frames, if that's more).
+//
+// e.g. hold every keyframe for c frames:
+//
+//   let out = [];
+//   for i in 0..len {
+//       if frames[i].keyframe { out.push(#{ frame: i, repeat: c }) } else { out.push(i) }
+//   }
+//   out
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use rand::Rng;
+use rhai::{Array, Dynamic, Engine, Map, Scope};
+
+use super::{ClipInfo, Frame, FrameType, Opt, Signal};
+
+/// Most times one action can repeat a frame
+const MAX_REPEAT: i64 = 10_000;
+/// Most frames the order can have, per frame going in
+const MAX_GROWTH: usize = 100;
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    // keep runaway scripts from hanging the gui
+    engine.set_max_operations(50_000_000);
+    engine.set_max_call_levels(64);
+    engine.set_max_array_size(10_000_000);
+    engine.set_max_map_size(10_000);
+    engine.set_max_string_size(1_000_000);
+    engine.on_print(|s| eprintln!("[script] {}", s));
+    engine.on_debug(|s, _, pos| eprintln!("[script {}] {}", pos, s));
+    engine
+}
+
+/// Compile only, for showing errors while the script is being edited
+pub fn check_script(source: &str) -> Result<(), String> {
+    engine().compile(source).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn frame_map(frame: &Frame, video_number: usize, clip: &ClipInfo) -> Map {
+    let fps = clip.fps;
+    let mut map = Map::new();
+    map.insert("index".into(), Dynamic::from(frame.index as i64));
+    map.insert("size".into(), Dynamic::from(frame.size as i64));
+    map.insert("type".into(), Dynamic::from(match frame.frame_type {
+        FrameType::Video => "video",
+        FrameType::Audio => "audio",
+        FrameType::Void => "void",
+    }));
+    map.insert("keyframe".into(), Dynamic::from(frame.keyframe));
+    map.insert("time".into(), Dynamic::from(if fps > 0.0 { video_number as f64 / fps } else { 0.0 }));
+    map.insert("loudness".into(), Dynamic::from(clip.audio_level(Signal::Loudness, frame).unwrap_or(0.0) as f64));
+    map.insert("onset".into(), Dynamic::from(clip.audio_level(Signal::Onset, frame).unwrap_or(0.0) as f64));
+    map
+}
+
+fn position(value: &Dynamic, len: usize) -> Result<usize, String> {
+    let i = value.as_int().map_err(|t| format!("expected a frame position, got {}", t))?;
+    if i < 0 || i as usize >= len {
+        return Err(format!("frame position {} out of range 0..{}", i, len));
+    }
+    Ok(i as usize)
+}
+
+/// Run `source` over `frames`, returning positions into `frames`
+pub fn run_script(source: &str, frames: &[Frame], opt: &Opt, clip: &ClipInfo) -> Result<Vec<usize>, String> {
+    let mut engine = engine();
+
+    let rng = Rc::new(RefCell::new(opt.rng()));
+    let r = rng.clone();
+    engine.register_fn("rand", move || r.borrow_mut().gen::<f64>());
+    let r = rng.clone();
+    engine.register_fn("rand", move |a: i64, b: i64| if b > a { r.borrow_mut().gen_range(a..b) } else { a });
+
+    let mut scope = Scope::new();
+    let mut video = 0usize;
+    let maps = frames.iter().map(|f| {
+        let number = video.saturating_sub((f.frame_type != FrameType::Video) as usize);
+        if f.frame_type == FrameType::Video {
+            video += 1;
+        }
+        Dynamic::from(frame_map(f, number, clip))
+    });
+    scope.push("frames", maps.collect::<Array>());
+    scope.push_constant("len", frames.len() as i64);
+    scope.push_constant("c", opt.countframes as i64);
+    scope.push_constant("n", opt.positframes as i64);
+    scope.push_constant("fps", clip.fps);
+
+    let result = engine.eval_with_scope::<Dynamic>(&mut scope, source).map_err(|e| e.to_string())?;
+    let items = result.try_cast::<Array>().ok_or("script must evaluate to an array")?;
+
+    let max_len = (frames.len() * MAX_GROWTH).max(MAX_REPEAT as usize);
+    let mut order = Vec::with_capacity(items.len().min(max_len));
+    for item in items {
+        if item.is_map() {
+            let action = item.cast::<Map>();
+            let frame = position(action.get("frame").ok_or("action map needs a 'frame' key")?, frames.len())?;
+            let repeat = action.get("repeat").map_or(Ok(1), |r| r.as_int()).map_err(|t| format!("repeat must be an int, got {}", t))?;
+            if repeat > MAX_REPEAT {
+                return Err(format!("repeat {} is more than {}", repeat, MAX_REPEAT));
+            }
+            order.extend(std::iter::repeat_n(frame, repeat.max(0) as usize));
+        } else {
+            order.push(position(&item, frames.len())?);
+        }
+        if order.len() > max_len {
+            return Err(format!("the order is more than {} frames, {} times the input", max_len, MAX_GROWTH));
+        }
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(types: &[FrameType]) -> Vec<Frame> {
+        types.iter().enumerate()
+            .map(|(i, t)| Frame { index: i, offset: i * 100, size: 10 + i, rel_size: 0.0, frame_type: t.clone(), keyframe: i == 0 })
+            .collect()
+    }
+
+    fn videos(n: usize) -> Vec<Frame> {
+        frames(&vec![FrameType::Video; n])
+    }
+
+    fn run(source: &str, frames: &[Frame], opt: &Opt) -> Result<Vec<usize>, String> {
+        run_script(source, frames, opt, &ClipInfo { fps: 10.0, ..Default::default() })
+    }
+
+    #[test]
+    fn plain_positions() {
+        let frames = videos(5);
+        assert_eq!(run("[4, 0, 2]", &frames, &Opt::default()), Ok(vec![4, 0, 2]));
+        let opt = Opt { countframes: 2, ..Default::default() };
+        assert_eq!(run("let out = []; for i in 0..len { out.push(len - 1 - i) } out.push(c); out", &frames, &opt), Ok(vec![4, 3, 2, 1, 0, 2]));
+    }
+
+    #[test]
+    fn repeat_and_drop_actions() {
+        let frames = videos(3);
+        assert_eq!(run("[#{ frame: 1, repeat: 3 }, #{ frame: 0, repeat: 0 }, #{ frame: 2 }]", &frames, &Opt::default()), Ok(vec![1, 1, 1, 2]));
+        assert!(run("[#{ frame: 0, repeat: 10001 }]", &frames, &Opt::default()).unwrap_err().contains("10000"));
+    }
+
+    #[test]
+    fn total_length_is_capped() {
+        let frames = videos(3);
+        let err = run("let out = []; for i in 0..2 { out.push(#{ frame: 0, repeat: 10000 }) } out", &frames, &Opt::default()).unwrap_err();
+        assert!(err.contains("more than 10000 frames"), "{}", err);
+    }
+
+    #[test]
+    fn seeded_rand_is_repeatable() {
+        let frames = videos(50);
+        let source = "let out = []; for i in 0..len { if rand() < 0.5 { out.push(rand(0, len)) } } out";
+        let seeded = |seed| run(source, &frames, &Opt { seed: Some(seed), ..Default::default() }).unwrap();
+        assert_eq!(seeded(3), seeded(3));
+        assert_ne!(seeded(3), seeded(4));
+    }
+
+    #[test]
+    fn time_counts_video_frames() {
+        let frames = frames(&[FrameType::Video, FrameType::Audio, FrameType::Video, FrameType::Audio, FrameType::Video]);
+        let times = "let out = []; for f in frames { out.push((f.time * 10.0).to_int()) } out";
+        assert_eq!(run(times, &frames, &Opt::default()), Ok(vec![0, 0, 1, 1, 2]));
+    }
+
+    #[test]
+    fn bad_results() {
+        let frames = videos(3);
+        assert!(run("42", &frames, &Opt::default()).unwrap_err().contains("array"));
+        assert!(run("[#{ repeat: 2 }]", &frames, &Opt::default()).unwrap_err().contains("'frame'"));
+        assert!(run("[3]", &frames, &Opt::default()).unwrap_err().contains("out of range"));
+        assert!(run("[\"one\"]", &frames, &Opt::default()).unwrap_err().contains("expected a frame position"));
+        assert!(run("[", &frames, &Opt::default()).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+// sweep.rs - every combination of a few step settings, for finding good ones
+//
+// Each parameter is a recipe step key with one or more values:
+//
+//   c=10..100:10      10, 20, .. 100 (both ends included)
+//   kill=0.3..0.9:0.2 0.3, 0.5, 0.7, 0.9
+//   n=1,5,20          a list
+//   mode=bloom        a single value
+//
+// `ramp`, `order`, `script`, `scope` and `beat_times` values are taken as is,
+// since commas and `..` mean something inside them.
+
+use rand::Rng;
+
+use super::recipe::RecipeStep;
+
+const VERBATIM_KEYS: [&str; 5] = ["ramp", "order", "script", "scope", "beat_times"];
+
+/// The most combinations `combinations` will list
+pub const MAX_COMBINATIONS: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepParam {
+    pub key: String,
+    pub values: Vec<String>,
+}
+
+fn decimals(s: &str) -> usize {
+    s.split_once('.').map_or(0, |(_, frac)| frac.len())
+}
+
+/// `a..b:step` as its values, None when `spec` isn't a numeric range
+fn expand_range(spec: &str) -> Option<Result<Vec<String>, String>> {
+    let (range, step) = spec.split_once(':')?;
+    let (start, end) = range.split_once("..")?;
+    let (start, end, step) = (start.trim(), end.trim(), step.trim());
+    let (a, b, s) = match (start.parse::<f64>(), end.parse::<f64>(), step.parse::<f64>()) {
+        (Ok(a), Ok(b), Ok(s)) => (a, b, s),
+        _ => return None,
+    };
+    if s <= 0.0 || b < a {
+        return Some(Err(format!("'{}' needs start <= end and a step above 0", spec)));
+    }
+    let count = ((b - a) / s + 1e-9).floor() as usize + 1;
+    if count > MAX_COMBINATIONS {
+        return Some(Err(format!("'{}' has {} values, that's too many", spec, count)));
+    }
+    // print with as many decimals as the range was written with, so 0.3 + 0.2 is 0.5
+    let places = decimals(start).max(decimals(end)).max(decimals(step));
+    Some(Ok((0..count).map(|i| format!("{:.*}", places, a + i as f64 * s)).collect()))
+}
+
+impl std::str::FromStr for SweepParam {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SweepParam, String> {
+        let (key, spec) = s.split_once('=').ok_or_else(|| format!("'{}' should look like key=values", s))?;
+        let key = key.trim().replace('-', "_");
+        let values = if VERBATIM_KEYS.contains(&key.as_str()) {
+            vec![spec.to_string()]
+        } else if let Some(range) = expand_range(spec) {
+            range?
+        } else {
+            spec.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect()
+        };
+        if values.is_empty() {
+            return Err(format!("'{}' has no values", s));
+        }
+        Ok(SweepParam { key, values })
+    }
+}
+
+/// Number of combinations of `params`, saturating
+pub fn combination_count(params: &[SweepParam]) -> usize {
+    params.iter().fold(1usize, |n, p| n.saturating_mul(p.values.len()))
+}
+
+/// The `index`th combination, the last parameter changing fastest
+fn combination(params: &[SweepParam], mut index: usize) -> Vec<(String, String)> {
+    let mut pairs = vec![(String::new(), String::new()); params.len()];
+    for (i, param) in params.iter().enumerate().rev() {
+        pairs[i] = (param.key.clone(), param.values[index % param.values.len()].clone());
+        index /= param.values.len();
+    }
+    pairs
+}
+
+/// Every combination of `params` as key=value pairs, or `sample` of them picked at random
+pub fn combinations<R: Rng>(params: &[SweepParam], sample: Option<usize>, rng: &mut R) -> Result<Vec<Vec<(String, String)>>, String> {
+    let total = combination_count(params);
+    let indices: Vec<usize> = match sample {
+        Some(n) if n < total => {
+            let mut picked = rand::seq::index::sample(rng, total, n).into_vec();
+            picked.sort_unstable();
+            picked
+        }
+        _ if total > MAX_COMBINATIONS => {
+            return Err(format!("{} combinations, sample at most {} of them", total, MAX_COMBINATIONS));
+        }
+        _ => (0..total).collect(),
+    };
+    Ok(indices.into_iter().map(|i| combination(params, i)).collect())
+}
+
+/// The step for one combination, with the parameters that vary in `name`
+pub struct SweepRun {
+    pub pairs: Vec<(String, String)>,
+    pub step: RecipeStep,
+    pub name: String,
+}
+
+impl SweepRun {
+    pub fn new(params: &[SweepParam], pairs: Vec<(String, String)>) -> Result<SweepRun, String> {
+        let step = RecipeStep::from_pairs(&pairs)?;
+        let varying: Vec<String> = params.iter().zip(&pairs)
+            .filter(|(param, (key, _))| param.values.len() > 1 && key != "mode")
+            .map(|(_, (key, value))| format!("{}{}", key, value.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_")))
+            .collect();
+        let name = std::iter::once(step.mode.clone()).chain(varying).collect::<Vec<_>>().join("-");
+        Ok(SweepRun { pairs, step, name })
+    }
+
+    /// The run as a `--step` argument
+    pub fn step_arg(&self) -> String {
+        self.pairs.iter()
+            .map(|(key, value)| if value.contains(char::is_whitespace) { format!("{}=\"{}\"", key, value) } else { format!("{}={}", key, value) })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn param(s: &str) -> SweepParam {
+        s.parse().unwrap()
+    }
+
+    fn values(s: &str) -> Vec<String> {
+        param(s).values
+    }
+
+    #[test]
+    fn ranges_include_the_end() {
+        assert_eq!(values("c=10..40:10"), ["10", "20", "30", "40"]);
+        assert_eq!(values("c=10..45:10"), ["10", "20", "30", "40"]);
+        assert_eq!(values("c=3..3:1"), ["3"]);
+    }
+
+    #[test]
+    fn ranges_print_like_they_were_written() {
+        assert_eq!(values("kill=0.3..0.9:0.2"), ["0.3", "0.5", "0.7", "0.9"]);
+        assert_eq!(values("kill=0..1:0.25"), ["0.00", "0.25", "0.50", "0.75", "1.00"]);
+    }
+
+    #[test]
+    fn bad_ranges() {
+        assert!("c=10..1:1".parse::<SweepParam>().unwrap_err().contains("start <= end"));
+        assert!("c=1..10:0".parse::<SweepParam>().unwrap_err().contains("step above 0"));
+        assert!("c=1..10:-2".parse::<SweepParam>().is_err());
+        assert!("c=0..1000000:1".parse::<SweepParam>().unwrap_err().contains("too many"));
+        assert!("c=".parse::<SweepParam>().unwrap_err().contains("no values"));
+        assert!("c".parse::<SweepParam>().is_err());
+    }
+
+    #[test]
+    fn lists_and_verbatim_keys() {
+        assert_eq!(param("kill-rel=0.1, 0.2,").key, "kill_rel");
+        assert_eq!(values("kill-rel=0.1, 0.2,"), ["0.1", "0.2"]);
+        assert_eq!(values("order=0..10:2, rev(3..5)"), ["0..10:2, rev(3..5)"]);
+        assert_eq!(values("scope=10..20"), ["10..20"]);
+        assert_eq!(values("ramp=1,4,1"), ["1,4,1"]);
+    }
+
+    #[test]
+    fn last_param_changes_fastest() {
+        let params = [param("c=1,2"), param("n=5,6,7")];
+        assert_eq!(combination_count(&params), 6);
+        let all = combinations(&params, None, &mut StdRng::seed_from_u64(1)).unwrap();
+        let flat: Vec<(String, String)> = all.iter().map(|p| (p[0].1.clone(), p[1].1.clone())).collect();
+        let expected = [("1", "5"), ("1", "6"), ("1", "7"), ("2", "5"), ("2", "6"), ("2", "7")];
+        assert_eq!(flat, expected.map(|(c, n)| (c.to_string(), n.to_string())));
+        assert!(all.iter().all(|p| p[0].0 == "c" && p[1].0 == "n"));
+    }
+
+    #[test]
+    fn sampling() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let params = [param("c=1..10:1"), param("n=1..10:1")];
+        let picked = combinations(&params, Some(5), &mut rng).unwrap();
+        assert_eq!(picked.len(), 5);
+        let mut unique = picked.clone();
+        unique.dedup();
+        assert_eq!(unique.len(), 5, "sorted and without repeats");
+        assert_eq!(combinations(&params, Some(500), &mut rng).unwrap().len(), 100);
+
+        // 1000 * 1000 is past MAX_COMBINATIONS, only a sample is allowed
+        let huge = [param("c=1..1000:1"), param("n=1..1000:1")];
+        assert!(combinations(&huge, None, &mut rng).unwrap_err().contains("sample"));
+        assert_eq!(combinations(&huge, Some(3), &mut rng).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn run_names_list_what_varies() {
+        let params = [param("mode=bloom,pulse"), param("c=10,20"), param("n=5"), param("order=0..3, 2*4")];
+        let pairs = combination(&params, 1);
+        let run = SweepRun::new(&params, pairs).unwrap();
+        assert_eq!(run.name, "bloom-c20");
+        assert_eq!(run.step.mode, "bloom");
+        assert_eq!(run.step_arg(), "mode=bloom c=20 n=5 order=\"0..3, 2*4\"");
+
+        let params = [param("mode=pulse"), param("kill=0.3,0.5"), param("scope=1..2,5..")];
+        let run = SweepRun::new(&params, combination(&params, 0)).unwrap();
+        assert_eq!(run.name, "pulse-kill0.3");
+
+        let params = [param("mode=void"), param("bogus=1,2")];
+        assert!(SweepRun::new(&params, combination(&params, 0)).is_err());
+    }
+}
@@ -1,51 +1,867 @@
 // tomatwo.rs
 
-use clap::Parser;
-use std::path::PathBuf;
-mod tomatwo_seed;
-use tomatwo_seed::{Opt as LibOpt, process_video};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use serde::Serialize;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tomatwo_seed::{Opt as LibOpt, AudioGate, Beats, KillMode, MarkovModel, Order, Overwrite, Ramp, Retime, RetimeTarget, Scope};
+use tomatwo_seed::{buffer_stdin, extract_frame_data, process_steps_report, read_layout, simulate_processing, ProcessReport};
+use tomatwo_seed::recipe::{Recipe, RecipeRetime, RecipeStep, RECIPE_VERSION};
+use tomatwo_seed::sweep::{self, SweepParam, SweepRun};
+use tomatwo_seed::inspect::AviReport;
+use tomatwo_seed::edl::write_edl;
+use tomatwo_seed::script::check_script;
+use tomatwo_seed::ffmpeg::{self, image_mosh, image_mosh_output, prep_output, PrepOptions};
 
 use std::io;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Write};
 
 #[derive(Parser, Debug)]
-#[command(name = "tomato")]
-struct Opt {
+#[command(name = "tomato", about = "Audio Video Interleave breaker")]
+struct Cli {
+    /// print one JSON result on stdout instead of the usual summary, logs stay on stderr
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// mosh an avi and write <input>-<mode>.avi
+    Process(ProcessArgs),
+
+    /// mosh an avi and play it with ffplay instead of writing it
+    Preview(ProcessArgs),
+
+    /// dry run on the frame table: how many frames and keyframes would be left
+    Simulate(ProcessArgs),
+
+    /// run a recipe on every video in a folder or glob, several at a time
+    Batch(BatchArgs),
+
+    /// render every combination of some settings, e.g. c=10..100:10 n=1,5,20 kill=0.3..0.9:0.2
+    Sweep(SweepArgs),
+
+    /// dump an avi's chunk tree, headers, index stats, keyframes and frame sizes
+    Inspect {
+        input: PathBuf,
+
+        /// also list every frame in movi
+        #[arg(long)]
+        frames: bool,
+    },
+
+    /// transcode anything ffmpeg reads into a moshable avi
+    Prep(PrepArgs),
+
+    /// convert a moshed avi into a playable mp4
+    Bake {
+        input: PathBuf,
+
+        /// defaults to the input with a .mp4 extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// lossless ultrafast x264 instead of slow crf 17
+        #[arg(long)]
+        fast: bool,
+    },
+
+    /// move a still image with the motion of an avi: the image becomes the only keyframe,
+    /// followed by all of the clip's delta frames
+    Melt {
+        /// still image (anything ffmpeg can read)
+        image: PathBuf,
+
+        /// avi to take the motion from, its codec settings are used to encode the image
+        motion: PathBuf,
+
+        /// defaults to <image>-melt-<motion>.avi next to the motion clip
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+// `scope` and the options after it describe one step, so they can't be mixed with -r or --step
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("single_step").multiple(true)))]
+struct ProcessArgs {
     #[arg(short, long)]
     input: PathBuf,
-    
-    #[arg(short, long, default_value = "void")]
+
+    /// transcode the input with `prep`'s defaults first if it isn't an avi
+    #[arg(long)]
+    prep: bool,
+
+    /// run the steps in this recipe (.toml or .json) instead of the step options below
+    #[arg(short, long, conflicts_with_all = ["step", "single_step"])]
+    recipe: Option<PathBuf>,
+
+    /// a step like "mode=bloom c=30 n=100 kill=0.5", repeat to chain steps in order.
+    /// Takes the keys of a recipe step, replaces the step options below
+    #[arg(long, conflicts_with = "single_step")]
+    step: Vec<RecipeStep>,
+
+    /// write the output here instead of next to the input, `-` for stdout
+    #[arg(short, long, conflicts_with = "name")]
+    output: Option<PathBuf>,
+
+    /// output file name from {stem} {mode} {params} {seed} {date} {n}, default "{stem}-{params}"
+    #[arg(long)]
+    name: Option<String>,
+
+    /// when the output file exists: overwrite, skip or increment
+    #[arg(long, default_value_t = Overwrite::Replace)]
+    overwrite: Overwrite,
+
+    /// write the steps being run to this recipe file (.toml or .json)
+    #[arg(long)]
+    save_recipe: Option<PathBuf>,
+
+    /// only touch these frames, e.g. 120..300 or 120..
+    #[arg(long, group = "single_step")]
+    scope: Option<Scope>,
+
+    #[arg(short, long, default_value = "void", group = "single_step")]
     mode: String,
-    
-    #[arg(short, default_value_t = 1)]
+
+    #[arg(short, long, default_value_t = 1, group = "single_step")]
     countframes: usize,
-    
-    #[arg(short, default_value_t = 1)]
+
+    #[arg(short, long, short_alias = 'n', default_value_t = 1, group = "single_step")]
     positframes: usize,
-    
-    #[arg(short)]
+
+    #[arg(short, long)]
     audio: bool,
-    
-    #[arg(long)]
+
+    #[arg(long, group = "single_step")]
     firstframe: bool,
-    
-    #[arg(short, default_value_t = 0.7)]
+
+    #[arg(short, long, default_value_t = 0.7, group = "single_step")]
     kill: f32,
-    
-    #[arg(short, default_value_t = 0.15)]
+
+    #[arg(long, default_value_t = 0.15, group = "single_step")]
     kill_rel: f32,
-    
-    #[arg(short, default_value_t = 1)]
+
+    #[arg(short = 'x', long, default_value_t = 1, group = "single_step")]
     multiply: i32,
-    
-    #[arg(short)]
-    preview: bool
+
+    /// turn keyframes into copies of the previous delta frame instead of killing big frames
+    #[arg(long, group = "single_step")]
+    mutate: bool,
+
+    /// with --mutate, leave the first N keyframes alone
+    #[arg(long, default_value_t = 0, group = "single_step")]
+    keep_keyframes: usize,
+
+    /// only kill where the input's PCM audio reaches this level, e.g. onset:0.3 drops keyframes on hits
+    #[arg(long, group = "single_step")]
+    kill_on: Option<AudioGate>,
+
+    /// custom frame order, e.g. "0..120, 120*30, rev(121..200), shuffle(200..260)". implies -m order
+    #[arg(long, group = "single_step")]
+    order: Option<String>,
+
+    /// assemble exactly the frame order in this EDL (.txt, .csv or .json) instead of moshing. not for simulate
+    #[arg(long)]
+    edl: Option<PathBuf>,
+
+    /// write the final frame order to an EDL (.txt, .csv or .json)
+    #[arg(long)]
+    export_edl: Option<PathBuf>,
+
+    /// rhai script that returns the frame order, see script.rs. implies -m script
+    #[arg(long, group = "single_step")]
+    script: Option<PathBuf>,
+
+    /// vary multiply per frame: flat, curve:1,4,1 (rates over time), size:0.5,3 (biggest frames, smallest frames)
+    /// or loud:1,4 / onset:1,6 (silence, loudest) from the input's PCM audio
+    #[arg(long, default_value = "flat", group = "single_step")]
+    ramp: Ramp,
+
+    /// drop/duplicate frames evenly at the end so the output has exactly this many video frames
+    #[arg(long, conflicts_with = "target_duration")]
+    target_frames: Option<usize>,
+
+    /// same, for a duration in seconds at the input's frame rate
+    #[arg(long)]
+    target_duration: Option<f64>,
+
+    /// hit --target-duration by rewriting the frame rate instead
+    #[arg(long, requires = "target_duration")]
+    retime_fps: bool,
+
+    /// transition model for -m markov: size (frames sized like the real next one) or adjacency (nearby frames)
+    #[arg(long, default_value = "size", group = "single_step")]
+    markov: MarkovModel,
+
+    /// seed for -m markov, same seed same walk
+    #[arg(long, group = "single_step")]
+    seed: Option<u64>,
+
+    /// put -m pulse/bloom on the beat at this tempo instead of every p frames
+    #[arg(long, conflicts_with = "beats", group = "single_step")]
+    bpm: Option<f64>,
+
+    /// seconds until the first beat
+    #[arg(long, default_value_t = 0.0, requires = "bpm", group = "single_step")]
+    beat_offset: f64,
+
+    /// same, with beat times in seconds from a file, one per line (audacity labels work)
+    #[arg(long, group = "single_step")]
+    beats: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// a folder, or a glob like "clips/**/*.mp4" (quote it)
+    input: String,
+
+    /// the recipe to run on every file
+    #[arg(short, long, required_unless_present = "step", conflicts_with = "step")]
+    recipe: Option<PathBuf>,
+
+    /// a step like "mode=bloom c=30 n=100", repeat to chain steps, instead of a recipe
+    #[arg(long)]
+    step: Vec<RecipeStep>,
+
+    /// write the outputs here instead of next to each input
+    #[arg(short, long)]
+    output_dir: Option<PathBuf>,
+
+    /// output file name from {stem} {mode} {params} {seed} {date} {n}, default "{stem}-{params}"
+    #[arg(long)]
+    name: Option<String>,
+
+    /// when an output file exists: overwrite, skip or increment
+    #[arg(long, default_value_t = Overwrite::Replace)]
+    overwrite: Overwrite,
+
+    /// files processed at once, defaults to one per core
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+struct SweepArgs {
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// transcode the input with `prep`'s defaults first if it isn't an avi
+    #[arg(long)]
+    prep: bool,
+
+    /// step keys with a range (a..b:step), a list (a,b,c) or one value, mode= included
+    #[arg(required = true)]
+    params: Vec<SweepParam>,
+
+    /// render this many combinations picked at random instead of all of them
+    #[arg(long)]
+    sample: Option<usize>,
+
+    /// seed for --sample
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// defaults to <input>-sweep/ next to the input
+    #[arg(short, long)]
+    output_dir: Option<PathBuf>,
+
+    /// when an output file exists: overwrite, skip or increment
+    #[arg(long, default_value_t = Overwrite::Replace)]
+    overwrite: Overwrite,
+
+    /// renders at once, defaults to one per core
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+struct PrepArgs {
+    input: PathBuf,
+
+    /// defaults to 🍅/<input file name>.avi next to the input, e.g. 🍅/clip.mp4.avi
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// ffmpeg video encoder
+    #[arg(long, default_value = "libxvid")]
+    codec: String,
+
+    /// -q:v, 1 (best) to 31
+    #[arg(short, long, default_value_t = 2)]
+    quality: u32,
+
+    /// max frames between keyframes
+    #[arg(short = 'g', long)]
+    keyint: Option<u32>,
+
+    #[arg(long)]
+    fps: Option<f64>,
+
+    /// scale to this width, keeping the aspect ratio
+    #[arg(long)]
+    width: Option<u32>,
+
+    /// keep audio as uncompressed PCM, for --ramp loud:.. and --kill-on
+    #[arg(long)]
+    pcm: bool,
+}
+
+impl ProcessArgs {
+    /// The steps, and warnings about the recipe they came from
+    fn into_steps(self, preview: bool) -> io::Result<(Vec<LibOpt>, Vec<String>)> {
+        let save_recipe = self.save_recipe.clone();
+        let (steps, warnings) = self.load_steps(preview)?;
+        if let Some(path) = save_recipe {
+            Recipe::from_steps(&steps).write(&path)?;
+            eprintln!("> Saved recipe to {:?}", path);
+        }
+        Ok((steps, warnings))
+    }
+
+    fn load_steps(self, preview: bool) -> io::Result<(Vec<LibOpt>, Vec<String>)> {
+        let (recipe, base_dir) = match &self.recipe {
+            Some(path) => (Recipe::read(path)?, path.parent().unwrap_or(std::path::Path::new("")).to_path_buf()),
+            None if !self.step.is_empty() => (step_recipe(&self.step, self.audio, self.retime()), PathBuf::new()),
+            None => return Ok((vec![self.into_lib_opt(preview)?], Vec::new())),
+        };
+        let mut steps = recipe
+            .to_steps(&self.input, &base_dir)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let mut warnings = recipe.warnings();
+        steps[0].edl_in = self.edl.clone();
+        // the whole-run options go on top of the recipe
+        if self.audio {
+            steps.iter_mut().for_each(|step| step.audio = true);
+        }
+        if let (Some(retime), Some(last)) = (self.retime(), steps.last_mut()) {
+            if last.retime.as_ref().is_some_and(|r| *r != retime) {
+                let warning = "--target-frames/--target-duration replace the recipe's retime".to_string();
+                eprintln!("> Warning: {}", warning);
+                warnings.push(warning);
+            }
+            last.retime = Some(retime);
+        }
+        if let Some(last) = steps.last_mut() {
+            last.edl_out = self.export_edl;
+            last.preview = preview;
+            last.output = self.output;
+            last.name_template = self.name;
+            last.overwrite = self.overwrite;
+        }
+        Ok((steps, warnings))
+    }
+
+    fn retime(&self) -> Option<Retime> {
+        self.target_frames.map(RetimeTarget::Frames)
+            .or(self.target_duration.map(RetimeTarget::Seconds))
+            .map(|target| Retime { target, rewrite_fps: self.retime_fps })
+    }
+
+    /// Validate the step's params and turn them into the library's options
+    fn into_lib_opt(self, preview: bool) -> io::Result<LibOpt> {
+        if let Some(order) = &self.order {
+            if let Err(e) = Order::parse(order) {
+                eprintln!("{}", e.pretty(order));
+                return Err(io::Error::new(ErrorKind::InvalidInput, e.to_string()));
+            }
+        }
+
+        let script = match &self.script {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+        if let Some(Err(e)) = script.as_deref().map(check_script) {
+            eprintln!("{}", e);
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Script failed to compile"));
+        }
+
+        let beats = match (self.bpm, &self.beats) {
+            (Some(bpm), _) => Some(Beats::Bpm { bpm, offset: self.beat_offset }),
+            (None, Some(path)) => Some(Beats::read_list(path)?),
+            (None, None) => None,
+        };
+
+        let retime = self.retime();
+        Ok(LibOpt {
+            input: self.input,
+            mode: if self.order.is_some() { "order".to_string() } else if script.is_some() { "script".to_string() } else { self.mode },
+            countframes: self.countframes,
+            positframes: self.positframes,
+            audio: self.audio,
+            firstframe: self.firstframe,
+            kill: self.kill,
+            kill_rel: self.kill_rel,
+            multiply: self.multiply,
+            preview,
+            kill_mode: if self.mutate { KillMode::Mutate } else { KillMode::Drop },
+            keep_keyframes: self.keep_keyframes,
+            order: self.order,
+            edl_in: self.edl,
+            edl_out: self.export_edl,
+            script,
+            ramp: self.ramp,
+            retime,
+            markov: self.markov,
+            seed: self.seed,
+            beats,
+            kill_on: self.kill_on,
+            scope: self.scope,
+            output_dir: None,
+            output: self.output,
+            name_template: self.name,
+            overwrite: self.overwrite,
+        })
+    }
+}
+
+/// A recipe made of `--step`s
+fn step_recipe(steps: &[RecipeStep], audio: bool, retime: Option<Retime>) -> Recipe {
+    Recipe {
+        version: RECIPE_VERSION,
+        audio,
+        steps: steps.to_vec(),
+        retime: retime.as_ref().map(RecipeRetime::from_retime),
+        ..Default::default()
+    }
+}
+
+/// Write to stdout, stopping quietly if the reader went away (piped into head or similar)
+fn write_stdout(text: &str) -> io::Result<()> {
+    match io::stdout().lock().write_all(text.as_bytes()) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// Print a command's result as its one JSON document
+fn print_json<T: Serialize>(result: &T) -> io::Result<()> {
+    let text = serde_json::to_string_pretty(result).map_err(io::Error::other)?;
+    write_stdout(&(text + "\n"))
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// `avi_input`, or stdin buffered into a temp file that lives as long as the returned guard
+fn read_input(input: &Path, prep: bool) -> io::Result<(PathBuf, Option<tempfile::TempPath>)> {
+    if is_stdio(input) {
+        let temp = buffer_stdin()?;
+        return Ok((temp.to_path_buf(), Some(temp)));
+    }
+    Ok((avi_input(input.to_path_buf(), prep)?, None))
+}
+
+/// The avi to work on: `input` itself, or a fresh `prep` of it when asked to
+fn avi_input(input: PathBuf, prep: bool) -> io::Result<PathBuf> {
+    if !input.exists() {
+        return Err(io::Error::new(ErrorKind::NotFound, "Input file not found"));
+    }
+    if input.extension().is_some_and(|e| e.eq_ignore_ascii_case("avi")) {
+        return Ok(input);
+    }
+    if !prep {
+        return Err(io::Error::new(ErrorKind::InvalidInput,
+            "Input is not an avi, run `tomatwo prep` on it first or pass --prep"));
+    }
+    let output = prep_output(&input);
+    eprintln!("> Transcoding {:?} with default prep settings...", input);
+    ffmpeg::to_avi(&input, &output, &PrepOptions::default())?;
+    eprintln!("> Transcoding successful: {:?}", output);
+    Ok(output)
+}
+
+fn process(args: ProcessArgs, preview: bool, json: bool) -> io::Result<()> {
+    let to_stdout = args.output.as_deref().is_some_and(is_stdio);
+    if is_stdio(&args.input) && args.output.is_none() && !preview {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Reading from stdin needs -o <file> or -o -"));
+    }
+    if to_stdout && json {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "--json and -o - would both write to stdout"));
+    }
+    let (input, _stdin) = read_input(&args.input, args.prep)?;
+    let (mut steps, warnings) = args.into_steps(preview)?;
+    steps.iter_mut().for_each(|step| step.input = input.clone());
+
+    eprintln!("> Processing video...");
+    let mut report = match process_steps_report(&steps) {
+        // whoever reads stdout stopped early, e.g. ffplay was closed
+        Err(e) if to_stdout && e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+        result => result?,
+    };
+    report.warnings.splice(0..0, warnings);
+    if json {
+        print_json(&report)?;
+    }
+    Ok(())
+}
+
+const VIDEO_EXTENSIONS: [&str; 10] = ["avi", "mp4", "mov", "mkv", "webm", "m4v", "mpg", "mpeg", "wmv", "flv"];
+
+fn is_video(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| VIDEO_EXTENSIONS.iter().any(|v| e.eq_ignore_ascii_case(v)))
+}
+
+/// The videos in folder `input`, or the ones matching it as a glob
+fn batch_inputs(input: &str) -> io::Result<Vec<PathBuf>> {
+    let dir = Path::new(input);
+    let mut files: Vec<PathBuf> = if dir.is_dir() {
+        std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_video(path))
+            .collect()
+    } else {
+        glob::glob(input)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Bad glob {:?}: {}", input, e)))?
+            .filter_map(Result::ok)
+            .filter(|path| is_video(path))
+            .collect()
+    };
+    files.sort();
+    if files.is_empty() {
+        return Err(io::Error::new(ErrorKind::NotFound, format!("No videos found in {:?}", input)));
+    }
+    Ok(files)
+}
+
+/// Run `f`, turning a panic into an error so one bad file doesn't stop the others
+fn guarded<T, F: FnOnce() -> io::Result<T>>(f: F) -> io::Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(io::Error::other(format!("panicked: {}", message)))
+    })
+}
+
+#[derive(Serialize)]
+struct BatchFile {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    error: Option<String>,
+    millis: f64,
+    report: Option<ProcessReport>,
+}
+
+#[derive(Serialize)]
+struct BatchReport {
+    ok: usize,
+    failed: usize,
+    millis: f64,
+    files: Vec<BatchFile>,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn batch_one(input: &Path, recipe: &Recipe, base_dir: &Path, args: &BatchArgs) -> io::Result<ProcessReport> {
+    let input = avi_input(input.to_path_buf(), true)?;
+    let mut steps = recipe.to_steps(&input, base_dir)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    if let Some(last) = steps.last_mut() {
+        last.output_dir = args.output_dir.clone();
+        last.name_template = args.name.clone();
+        last.overwrite = args.overwrite;
+    }
+    Ok(process_steps_report(&steps)?)
+}
+
+fn batch(args: BatchArgs, json: bool) -> io::Result<()> {
+    let (recipe, base_dir) = match &args.recipe {
+        Some(path) => (Recipe::read(path)?, path.parent().unwrap_or(Path::new("")).to_path_buf()),
+        None => (step_recipe(&args.step, false, None), PathBuf::new()),
+    };
+    // catch a bad recipe once instead of failing every file on it
+    recipe.to_steps(Path::new(""), &base_dir).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+    let files = batch_inputs(&args.input)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.unwrap_or(0))
+        .build()
+        .map_err(io::Error::other)?;
+    eprintln!("> Batch: {} files, {} at a time", files.len(), pool.current_num_threads());
+
+    let timer = Instant::now();
+    let files: Vec<BatchFile> = pool.install(|| files.par_iter().map(|input| {
+        let start = Instant::now();
+        let result = guarded(|| batch_one(input, &recipe, &base_dir, &args));
+        let (output, error) = match &result {
+            Ok(report) => (Some(report.output.clone()), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        BatchFile { input: input.clone(), output, error, millis: millis(start.elapsed()), report: result.ok() }
+    }).collect());
+
+    let failed = files.iter().filter(|f| f.error.is_some()).count();
+    let report = BatchReport { ok: files.len() - failed, failed, millis: millis(timer.elapsed()), files };
+    if json {
+        print_json(&report)?;
+    } else {
+        println!();
+        println!("{:<6}  {:>10}  input -> output", "status", "time");
+        for file in &report.files {
+            match (&file.output, &file.error) {
+                (Some(output), _) => println!("{:<6}  {:>8.1}ms  {} -> {}", "ok", file.millis, file.input.display(), output.display()),
+                (None, error) => println!("{:<6}  {:>8.1}ms  {}: {}", "FAILED", file.millis, file.input.display(), error.as_deref().unwrap_or_default()),
+            }
+        }
+        println!("> {} ok, {} failed, {:.1}ms total", report.ok, report.failed, report.millis);
+    }
+
+    if failed > 0 {
+        let message = format!("{} of {} files failed", failed, report.files.len());
+        if json {
+            // the report above already says which, an error document would be a second result
+            eprintln!("> {}", message);
+            std::process::exit(1);
+        }
+        return Err(io::Error::other(message));
+    }
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[derive(Serialize)]
+struct SweepRender {
+    file: Option<PathBuf>,
+    params: Vec<(String, String)>,
+    step: String,
+    error: Option<String>,
+    report: Option<ProcessReport>,
+}
+
+#[derive(Serialize)]
+struct SweepReport {
+    output_dir: PathBuf,
+    index: PathBuf,
+    combinations: usize,
+    rendered: usize,
+    failed: usize,
+    millis: f64,
+    renders: Vec<SweepRender>,
+}
+
+fn sweep(args: SweepArgs, json: bool) -> io::Result<()> {
+    let input = avi_input(args.input.clone(), args.prep)?;
+    let invalid = |e: String| io::Error::new(ErrorKind::InvalidInput, e);
+
+    let mut rng = LibOpt { seed: args.seed, ..Default::default() }.rng();
+    let runs = sweep::combinations(&args.params, args.sample, &mut rng).map_err(invalid)?
+        .into_iter()
+        .map(|pairs| SweepRun::new(&args.params, pairs))
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(invalid)?;
+    for run in &runs {
+        run.step.to_opt(&input, Path::new("")).map_err(|e| invalid(format!("{}: {}", run.step_arg(), e)))?;
+    }
+
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let output_dir = args.output_dir.clone().unwrap_or_else(|| input.with_file_name(format!("{}-sweep", stem)));
+    std::fs::create_dir_all(&output_dir)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.unwrap_or(0))
+        .build()
+        .map_err(io::Error::other)?;
+    eprintln!("> Sweep: {} of {} combinations into {:?}", runs.len(), sweep::combination_count(&args.params), output_dir);
+
+    let timer = Instant::now();
+    let results: Vec<io::Result<ProcessReport>> = pool.install(|| runs.par_iter().map(|run| guarded(|| {
+        let mut opt = run.step.to_opt(&input, Path::new("")).map_err(invalid)?;
+        opt.output = Some(output_dir.join(format!("{}-{}.avi", stem, run.name)));
+        opt.overwrite = args.overwrite;
+        Ok(process_steps_report(&[opt])?)
+    })).collect());
+
+    // one row per render with the values that made it, and a --step to turn it into a recipe
+    let mut index = String::from("file");
+    for param in &args.params {
+        index += &format!(",{}", csv_field(&param.key));
+    }
+    index += ",step\n";
+    let mut failed = 0;
+    for (run, result) in runs.iter().zip(&results) {
+        match result {
+            Ok(report) => {
+                index += &csv_field(&report.output.file_name().unwrap_or_default().to_string_lossy());
+                for (_, value) in &run.pairs {
+                    index += &format!(",{}", csv_field(value));
+                }
+                index += &format!(",{}\n", csv_field(&run.step_arg()));
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("> Failed: {}: {}", run.step_arg(), e);
+            }
+        }
+    }
+    let index_path = output_dir.join("index.csv");
+    std::fs::write(&index_path, index)?;
+
+    if json {
+        let renders = runs.iter().zip(results).map(|(run, result)| {
+            let (file, error) = match &result {
+                Ok(report) => (Some(report.output.clone()), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            SweepRender { file, params: run.pairs.clone(), step: run.step_arg(), error, report: result.ok() }
+        }).collect();
+        print_json(&SweepReport {
+            output_dir,
+            index: index_path,
+            combinations: sweep::combination_count(&args.params),
+            rendered: runs.len() - failed,
+            failed,
+            millis: millis(timer.elapsed()),
+            renders,
+        })?;
+    } else {
+        println!("> {} rendered, {} failed, {:.2?} total", runs.len() - failed, failed, timer.elapsed());
+        println!("> Index: {:?}, make a recipe from a row with `tomatwo process --step \"<step>\" --save-recipe <file>`", index_path);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct SimulateReport {
+    input: PathBuf,
+    input_frames: usize,
+    output_frames: usize,
+    keyframes: usize,
+    fps: f64,
+    input_seconds: Option<f64>,
+    output_seconds: Option<f64>,
+    edl: Option<PathBuf>,
+    warnings: Vec<String>,
+}
+
+fn simulate(args: ProcessArgs, json: bool) -> io::Result<()> {
+    if args.edl.is_some() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "simulate can't replay an EDL, use process --edl"));
+    }
+    let (input, _stdin) = read_input(&args.input, args.prep)?;
+    let (steps, warnings) = args.into_steps(false)?;
+    for step in &steps {
+        step.validate()?;
+    }
+
+    let (frames, _) = extract_frame_data(&input)?;
+    let fps = read_layout(&input).map(|l| l.fps()).unwrap_or(0.0);
+    let before = frames.len();
+    let frames = simulate_processing(frames, &steps);
+    let edl = steps.last().and_then(|s| s.edl_out.clone());
+    if let Some(path) = &edl {
+        write_edl(path, &frames)?;
+        eprintln!("> Frame order written to {:?}", path);
+    }
+
+    let seconds = |n: usize| (fps > 0.0).then(|| n as f64 / fps);
+    let report = SimulateReport {
+        input,
+        input_frames: before,
+        output_frames: frames.len(),
+        keyframes: frames.iter().filter(|f| f.keyframe).count(),
+        fps,
+        input_seconds: seconds(before),
+        output_seconds: seconds(frames.len()),
+        edl,
+        warnings,
+    };
+    if json {
+        return print_json(&report);
+    }
+    println!("> {} -> {} video frames, {} keyframes left", report.input_frames, report.output_frames, report.keyframes);
+    if let (Some(before), Some(after)) = (report.input_seconds, report.output_seconds) {
+        println!("> {:.2}s -> {:.2}s at {:.3} fps", before, after, fps);
+    }
+    Ok(())
+}
+
+fn inspect(input: &Path, json: bool, frames: bool) -> io::Result<()> {
+    let (path, stdin) = read_input(input, false)?;
+    let mut report = AviReport::read(&path, frames)?;
+    if stdin.is_some() {
+        report.file = "stdin".to_string();
+    }
+    if json {
+        print_json(&report)
+    } else {
+        write_stdout(&report.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct FileReport {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+fn prep(args: PrepArgs, json: bool) -> io::Result<()> {
+    let output = args.output.unwrap_or_else(|| prep_output(&args.input));
+    let prep = PrepOptions {
+        codec: args.codec,
+        quality: args.quality,
+        keyint: args.keyint,
+        fps: args.fps,
+        width: args.width,
+        pcm_audio: args.pcm,
+    };
+    ffmpeg::to_avi(&args.input, &output, &prep)?;
+    eprintln!("> Prepped: {:?}", output);
+    if json {
+        print_json(&FileReport { input: args.input, output })?;
+    }
+    Ok(())
+}
+
+fn run(command: Command, json: bool) -> io::Result<()> {
+    match command {
+        Command::Process(args) => process(args, false, json),
+        Command::Preview(args) => process(args, true, json),
+        Command::Simulate(args) => simulate(args, json),
+        Command::Batch(args) => batch(args, json),
+        Command::Sweep(args) => sweep(args, json),
+        Command::Inspect { input, frames } => inspect(&input, json, frames),
+        Command::Prep(args) => prep(args, json),
+        Command::Bake { input, output, fast } => {
+            let output = output.unwrap_or_else(|| input.with_extension("mp4"));
+            ffmpeg::to_mp4(&input, &output, fast)?;
+            eprintln!("> Baked: {:?}", output);
+            if json {
+                print_json(&FileReport { input, output })?;
+            }
+            Ok(())
+        }
+        Command::Melt { image, motion, output } => {
+            let output = output.unwrap_or_else(|| image_mosh_output(&image, &motion));
+            let (output, warnings) = image_mosh(&image, &motion, &output)?;
+            if json {
+                print_json(&serde_json::json!({ "image": image, "motion": motion, "output": output, "warnings": warnings }))?;
+            }
+            Ok(())
+        }
+    }
 }
 
 fn main() -> std::io::Result<()> {
-    let opt = Opt::parse();
-    
-    println!(r#"
+    let cli = Cli::parse();
+
+    if !cli.json && !matches!(cli.command, Command::Inspect { .. }) {
+        eprintln!(r#"
     tomatwo - ufffd's rusty n dusty tomato fork
      _                        _        
     | |_ ___  _ __ ___   __ _| |_       _____  
@@ -66,41 +882,19 @@ fn main() -> std::io::Result<()> {
     ig. https://www.instagram.com/kaspar.wtf 
     ___________________________________
     "#);
+    }
 
-    let mut lib_opt = LibOpt {
-        input: opt.input,
-        mode: opt.mode,
-        countframes: opt.countframes,
-        positframes: opt.positframes,
-        audio: opt.audio,
-        firstframe: opt.firstframe,
-        kill: opt.kill,
-        kill_rel: opt.kill_rel,
-        multiply: opt.multiply,
-        preview: opt.preview
-    };
-
-    // check if input exists and is an avi file
-    if !lib_opt.input.exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "Input file not found"));
-    }
-    if lib_opt.input.extension().unwrap_or_default() != "avi" {
-        // default prep with ffmpeg
-        println!("> Input file is not an avi file. Attempting to transcode with default ffmpeg settings...");
-        // replace extension with avi
-        let file_name = format!("{}.avi", lib_opt.input.file_stem().unwrap_or_default().to_str().unwrap_or_default());
-        let ffmpeg = std::process::Command::new("ffmpeg")
-            .args(&["-i", lib_opt.input.to_str().unwrap(), "-f", "avi", "-pix_fmt", "yuv420p", "-y", &file_name])
-            .output()?;
-        if !ffmpeg.status.success() {
-            return Err(io::Error::new(ErrorKind::Other, "FFmpeg failed to transcode input file"));
+    let json = cli.json;
+    match run(cli.command, json) {
+        Err(e) if json => {
+            // the failure is the result, and the exit code still says so
+            print_json(&serde_json::json!({ "error": e.to_string() }))?;
+            std::process::exit(1);
         }
-        println!("> Transcoding successful: {}", file_name);
-        lib_opt.input = PathBuf::from(file_name);
+        Err(e) => {
+            eprintln!("> Error: {}", e);
+            std::process::exit(1);
+        }
+        Ok(()) => Ok(()),
     }
-
-    println!("> Processing video...");
-    let output_path = process_video(&lib_opt)?;
-
-    Ok(())
-}
\ No newline at end of file
+}
@@ -1,13 +1,34 @@
 // tomatwo_lib.rs
+use std::cell::RefCell;
 use std::process::{Command, Stdio};
 use std::fs::File;
 use std::io::{self, Write, BufReader, BufWriter};
 use std::path::PathBuf;
 use memmap2::{Mmap, MmapOptions};
 use rayon::prelude::*;
-use tempfile;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+pub mod audio;
+pub mod avi;
+pub mod edl;
+pub mod error;
+pub mod ffmpeg;
+pub mod frames;
+pub mod inspect;
+pub mod order;
+pub mod output;
+pub mod recipe;
+pub mod script;
+pub mod sweep;
+pub use frames::{Frames, AviFrame};
+pub use order::{Order, OrderError};
+pub use audio::{AudioEnvelope, Signal};
+pub use output::Overwrite;
+pub use error::TomatwoError;
 
 const MOVI_MARKER: &[u8] = b"movi";
 const IDX1_MARKER: &[u8] = b"idx1";
@@ -26,42 +47,506 @@ pub struct Opt {
     pub multiply: i32,
     pub kill_rel: f32,
     pub preview: bool,
+    pub kill_mode: KillMode,
+    /// with `KillMode::Mutate`, keyframes left as they are
+    pub keep_keyframes: usize,
+    /// order expression for the "order" mode, see `order.rs`
+    pub order: Option<String>,
+    /// assemble exactly the frames listed in this EDL instead of killing and moshing
+    pub edl_in: Option<PathBuf>,
+    /// write the final frame order here
+    pub edl_out: Option<PathBuf>,
+    /// rhai source for the "script" mode, see `script.rs`
+    pub script: Option<String>,
+    /// how `multiply` varies over the clip
+    pub ramp: Ramp,
+    /// drop/duplicate frames (or change the frame rate) after everything else to hit a length
+    pub retime: Option<Retime>,
+    /// transition model for the "markov" mode
+    pub markov: MarkovModel,
+    /// fixed seed for the random modes (random, jiggle, markov, blockshuffle, order's shuffle), random each run when unset
+    pub seed: Option<u64>,
+    /// put "pulse"/"bloom" on these beats instead of every n frames
+    pub beats: Option<Beats>,
+    /// only kill frames where the source audio is at least this loud/punchy
+    pub kill_on: Option<AudioGate>,
+    /// only touch these positions of the step's input, the rest passes through
+    pub scope: Option<Scope>,
+    /// write the output into this folder instead of next to the input
+    pub output_dir: Option<PathBuf>,
+    /// write the output to exactly this file, `-` streams it to stdout
+    pub output: Option<PathBuf>,
+    /// output file name, see `output.rs`, `output::DEFAULT_TEMPLATE` when unset
+    pub name_template: Option<String>,
+    /// what to do when the output file already exists
+    pub overwrite: Overwrite,
+}
+
+impl Default for Opt {
+    fn default() -> Self {
+        Opt {
+            input: PathBuf::new(),
+            mode: "void".to_string(),
+            countframes: 1,
+            positframes: 1,
+            audio: false,
+            firstframe: false,
+            kill: 0.7,
+            multiply: 1,
+            kill_rel: 0.15,
+            preview: false,
+            kill_mode: KillMode::Drop,
+            keep_keyframes: 0,
+            order: None,
+            edl_in: None,
+            edl_out: None,
+            script: None,
+            ramp: Ramp::Flat,
+            retime: None,
+            markov: MarkovModel::Size,
+            seed: None,
+            beats: None,
+            kill_on: None,
+            scope: None,
+            output_dir: None,
+            output: None,
+            name_template: None,
+            overwrite: Overwrite::Replace,
+        }
+    }
+}
+
+/// Every mode `process_frames` knows
+pub const MODES: [&str; 20] = [
+    "void", "random", "reverse", "invert", "bloom", "pulse", "jiggle", "markov", "overlap", "pingpong",
+    "stutter", "stride", "sort", "rsort", "blockshuffle", "echo", "automosh", "loop", "order", "script",
+];
+
+impl Opt {
+    pub fn rng(&self) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// Whether this step reads the source's audio envelope
+    pub fn uses_audio(&self) -> bool {
+        self.kill_on.is_some() || self.script.is_some() || matches!(self.ramp, Ramp::Audio { .. })
+    }
+
+    /// Check everything that can be checked without the video, so a bad setting
+    /// fails before any work instead of panicking or quietly doing something else
+    pub fn validate(&self) -> error::Result<()> {
+        if !MODES.contains(&self.mode.as_str()) {
+            return Err(TomatwoError::bad_param("mode", format!("unknown mode '{}', use one of {}", self.mode, MODES.join(", "))));
+        }
+        if self.positframes == 0 && (self.mode == "overlap" || self.mode == "pulse" && self.beats.is_none()) {
+            return Err(TomatwoError::bad_param("n", format!("{} needs n of at least 1", self.mode)));
+        }
+        if self.mode == "order" {
+            Order::parse(self.order.as_deref().unwrap_or("")).map_err(|e| TomatwoError::bad_param("order", e.to_string()))?;
+        }
+        if self.mode == "script" {
+            script::check_script(self.script.as_deref().unwrap_or("")).map_err(|e| TomatwoError::bad_param("script", e))?;
+        }
+        if !(self.kill.is_finite() && self.kill >= 0.0) {
+            return Err(TomatwoError::bad_param("kill", format!("{} should be a fraction of the biggest frame, 0 or more", self.kill)));
+        }
+        if !(self.kill_rel.is_finite() && self.kill_rel >= 0.0) {
+            return Err(TomatwoError::bad_param("kill_rel", format!("{} should be a size increase, 0 or more", self.kill_rel)));
+        }
+        if self.multiply < 0 {
+            return Err(TomatwoError::bad_param("multiply", "can't be negative"));
+        }
+        let rates = match &self.ramp {
+            Ramp::Flat => Vec::new(),
+            Ramp::Curve(points) if points.is_empty() => return Err(TomatwoError::bad_param("ramp", "curve needs at least one rate")),
+            Ramp::Curve(points) => points.clone(),
+            Ramp::Size { min, max } | Ramp::Audio { min, max, .. } => vec![*min, *max],
+        };
+        if rates.iter().any(|r| !(r.is_finite() && *r >= 0.0)) {
+            return Err(TomatwoError::bad_param("ramp", "rates can't be negative"));
+        }
+        match self.retime.as_ref().map(|r| &r.target) {
+            Some(RetimeTarget::Frames(0)) => return Err(TomatwoError::bad_param("retime", "needs at least 1 frame")),
+            Some(RetimeTarget::Seconds(secs)) if !(secs.is_finite() && *secs > 0.0) => {
+                return Err(TomatwoError::bad_param("retime", "needs a duration above 0"));
+            }
+            _ => {}
+        }
+        if let Some(Beats::Bpm { bpm, offset }) = &self.beats {
+            if !(bpm.is_finite() && *bpm > 0.0 && offset.is_finite()) {
+                return Err(TomatwoError::bad_param("beats", "bpm has to be above 0"));
+            }
+        }
+        if self.kill_on.is_some_and(|gate| !gate.threshold.is_finite()) {
+            return Err(TomatwoError::bad_param("kill_on", "the threshold has to be a number"));
+        }
+        if let Some(scope) = &self.scope {
+            if scope.end.is_some_and(|end| end < scope.start) {
+                return Err(TomatwoError::bad_param("scope", format!("{} ends before it starts", scope)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a step gets rid of i-frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KillMode {
+    /// delete frames caught by `kill`/`kill_rel` (tomato.py behaviour, shortens the video)
+    Drop,
+    /// replace each flagged keyframe after the first `keep_keyframes` with the previous
+    /// delta frame, like `Frames::mutate_keyframes_into_deltas`. Keeps frame count and audio sync
+    Mutate,
+}
+
+/// Per-frame duplication rate. Fractional rates average out through error
+/// diffusion, and rates under 1 drop frames.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ramp {
+    /// every frame `multiply` times
+    Flat,
+    /// rate keypoints spread evenly from the first to the last frame, linearly interpolated
+    Curve(Vec<f32>),
+    /// rate from frame size: the smallest video frames get `max`, the biggest get `min`
+    Size { min: f32, max: f32 },
+    /// rate from the source's audio: silence gets `min`, the loudest frame (or biggest hit) gets `max`
+    Audio { signal: Signal, min: f32, max: f32 },
+}
+
+impl std::str::FromStr for Ramp {
+    type Err = String;
+
+    /// `flat`, `curve:1,4,1`, `size:0.5,3`, `loud:1,4` or `onset:1,6`
+    fn from_str(s: &str) -> Result<Ramp, String> {
+        let (kind, args) = s.split_once(':').unwrap_or((s, ""));
+        let numbers = args.split(',')
+            .filter(|a| !a.trim().is_empty())
+            .map(|a| a.trim().parse::<f32>().map_err(|_| format!("'{}' is not a number", a.trim())))
+            .collect::<Result<Vec<f32>, String>>()?;
+        if numbers.iter().any(|r| *r < 0.0) {
+            return Err("ramp rates can't be negative".to_string());
+        }
+        match (kind.trim(), numbers.as_slice()) {
+            ("flat", []) => Ok(Ramp::Flat),
+            ("curve", [_, ..]) => Ok(Ramp::Curve(numbers)),
+            ("size", [min, max]) => Ok(Ramp::Size { min: *min, max: *max }),
+            ("loud" | "onset", [min, max]) => Ok(Ramp::Audio { signal: kind.parse()?, min: *min, max: *max }),
+            ("curve", _) => Err("curve needs at least one rate, e.g. curve:1,4,1".to_string()),
+            ("size", _) => Err("size needs a min and max rate, e.g. size:0.5,3".to_string()),
+            ("loud" | "onset", _) => Err(format!("{} needs a min and max rate, e.g. {}:1,4", kind.trim(), kind.trim())),
+            (other, _) => Err(format!("unknown ramp '{}', use flat, curve:.., size:.., loud:.. or onset:..", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Ramp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ramp::Flat => write!(f, "flat"),
+            Ramp::Curve(points) => write!(f, "curve:{}", points.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(",")),
+            Ramp::Size { min, max } => write!(f, "size:{},{}", min, max),
+            Ramp::Audio { signal, min, max } => write!(f, "{}:{},{}", signal, min, max),
+        }
+    }
+}
+
+/// Range of frame positions a step works on, `120..300` or `120..`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scope {
+    pub start: usize,
+    pub end: Option<usize>,
+}
+
+impl Scope {
+    /// The scope as a range into a list of `len` frames
+    pub fn clamp(&self, len: usize) -> std::ops::Range<usize> {
+        let start = self.start.min(len);
+        start..self.end.unwrap_or(len).clamp(start, len)
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scope, String> {
+        let (start, end) = s.split_once("..").ok_or_else(|| format!("'{}' should look like 120..300 or 120..", s))?;
+        let number = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("'{}' is not a frame number", n.trim()));
+        let start = if start.trim().is_empty() { 0 } else { number(start)? };
+        let end = if end.trim().is_empty() { None } else { Some(number(end)?) };
+        if end.is_some_and(|end| end < start) {
+            return Err(format!("scope {} ends before it starts", s));
+        }
+        Ok(Scope { start, end })
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}..{}", self.start, end),
+            None => write!(f, "{}..", self.start),
+        }
+    }
+}
+
+/// Audio level a frame needs for something to happen to it, e.g. `onset:0.3`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioGate {
+    pub signal: Signal,
+    pub threshold: f32,
+}
+
+impl std::str::FromStr for AudioGate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AudioGate, String> {
+        let (signal, threshold) = s.split_once(':')
+            .ok_or_else(|| format!("'{}' should look like onset:0.3 or loud:0.5", s))?;
+        let threshold = threshold.trim().parse::<f32>()
+            .map_err(|_| format!("'{}' is not a number", threshold.trim()))?;
+        Ok(AudioGate { signal: signal.parse()?, threshold })
+    }
+}
+
+impl std::fmt::Display for AudioGate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.signal, self.threshold)
+    }
+}
+
+/// How the "markov" mode picks the next frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkovModel {
+    /// frames about the size of the one that really comes next, so the motion keeps its rhythm
+    Size,
+    /// frames near the current one in the source, twice as likely forwards as backwards
+    Adjacency,
+}
+
+impl std::str::FromStr for MarkovModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MarkovModel, String> {
+        match s.trim() {
+            "size" => Ok(MarkovModel::Size),
+            "adjacency" | "adjacent" => Ok(MarkovModel::Adjacency),
+            other => Err(format!("unknown markov model '{}', use size or adjacency", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for MarkovModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkovModel::Size => write!(f, "size"),
+            MarkovModel::Adjacency => write!(f, "adjacency"),
+        }
+    }
+}
+
+/// Beat grid for syncing "pulse" and "bloom" to music
+#[derive(Clone, Debug, PartialEq)]
+pub enum Beats {
+    /// steady tempo, first beat `offset` seconds in
+    Bpm { bpm: f64, offset: f64 },
+    /// beat times in seconds
+    List(Vec<f64>),
+}
+
+/// Frame rate to place beats with when there's no header to read it from (the gui's simulation)
+const FALLBACK_FPS: f64 = 25.0;
+
+impl Beats {
+    /// One time per line, in seconds. Only the first number on a line counts, so
+    /// label tracks exported from Audacity (start, end, label) work as they are.
+    pub fn parse_list(text: &str) -> Result<Beats, String> {
+        let mut times = Vec::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let first = line.split(|c: char| c.is_whitespace() || c == ',').next().unwrap_or_default();
+            let time = first.parse::<f64>()
+                .map_err(|_| format!("beat list line {}: '{}' is not a time in seconds", line_no + 1, first))?;
+            times.push(time);
+        }
+        times.sort_by(|a, b| a.total_cmp(b));
+        Ok(Beats::List(times))
+    }
+
+    pub fn read_list(path: &std::path::Path) -> io::Result<Beats> {
+        Beats::parse_list(&std::fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Output video frame numbers the beats land on, up to `max_frames`
+    pub fn frame_numbers(&self, fps: f64, max_frames: usize) -> Vec<usize> {
+        let fps = if fps > 0.0 { fps } else { FALLBACK_FPS };
+        let mut numbers: Vec<usize> = match self {
+            Beats::Bpm { bpm, .. } if *bpm <= 0.0 => Vec::new(),
+            Beats::Bpm { bpm, offset } => {
+                let period = 60.0 / bpm * fps;
+                (0..).map(|k| ((offset * fps) + k as f64 * period).round())
+                    .skip_while(|f| *f < 0.0)
+                    .take_while(|f| *f < max_frames as f64)
+                    .map(|f| f as usize)
+                    .collect()
+            }
+            Beats::List(times) => times.iter()
+                .map(|t| (t * fps).round())
+                .filter(|f| *f >= 0.0 && *f < max_frames as f64)
+                .map(|f| f as usize)
+                .collect(),
+        };
+        numbers.dedup();
+        numbers
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RetimeTarget {
+    Frames(usize),
+    Seconds(f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retime {
+    pub target: RetimeTarget,
+    /// hit a duration by rewriting the frame rate instead of dropping/duplicating
+    /// frames. audio will drift, and it doesn't apply to frame count targets
+    pub rewrite_fps: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct Frame {
+    /// position in the source frame table
+    pub index: usize,
     pub offset: usize,
     pub size: usize,
     pub rel_size: f32,
     pub frame_type: FrameType,
+    pub keyframe: bool,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FrameType {
     Video,
     Audio,
     Void,
 }
 
+/// Facts about the source clip that effects can use beyond the frame list
+#[derive(Clone, Debug, Default)]
+pub struct ClipInfo {
+    pub fps: f64,
+    /// levels of the source's PCM audio, indexed like the frame table
+    pub audio: Option<AudioEnvelope>,
+    /// what went wrong but didn't stop processing, for `ProcessReport`
+    pub warnings: RefCell<Vec<String>>,
+}
+
+impl ClipInfo {
+    pub fn from_layout(layout: &avi::AviLayout) -> ClipInfo {
+        ClipInfo { fps: layout.fps(), ..Default::default() }
+    }
+
+    /// Log a warning and keep it for the report
+    pub fn warn(&self, message: String) {
+        eprintln!("> Warning: {}", message);
+        self.warnings.borrow_mut().push(message);
+    }
+
+    pub fn audio_level(&self, signal: Signal, frame: &Frame) -> Option<f32> {
+        self.audio.as_ref().map(|a| a.get(signal, frame.index))
+    }
+}
+
+pub fn read_layout(path: &PathBuf) -> io::Result<avi::AviLayout> {
+    let file = File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    avi::parse_avi(&mmap)
+}
+
+/// Copy idx1 keyframe flags onto a frame table, matching video frames in order
+pub fn mark_keyframes(frames: &mut [Frame], layout: &avi::AviLayout) {
+    let mut flags = layout.index.iter()
+        .filter(|e| &e.id[2..] == b"dc" || &e.id[2..] == b"db")
+        .map(|e| e.is_keyframe());
+    for frame in frames.iter_mut().filter(|f| f.frame_type == FrameType::Video) {
+        frame.keyframe = flags.next().unwrap_or(false);
+    }
+}
+
+/// How many times bigger than its neighbours a keyframe has to be to count as a scene cut
+pub const SCENE_CUT_SPIKE: f32 = 2.0;
+const SCENE_CUT_WINDOW: usize = 4;
+
+/// Positions of video frames that look like scene cuts: keyframes (or any frame,
+/// if the clip has no keyframe flags) at least `spike` times the median size of
+/// the video frames around them. The very first keyframe is the start, not a cut.
+pub fn detect_scene_cuts(frames: &[Frame], spike: f32) -> Vec<usize> {
+    let video: Vec<usize> = frames.iter().enumerate()
+        .filter(|(_, f)| f.frame_type == FrameType::Video)
+        .map(|(i, _)| i)
+        .collect();
+    let has_flags = video.iter().any(|&i| frames[i].keyframe);
+
+    video.iter().enumerate().skip(1).filter_map(|(v, &i)| {
+        if has_flags && !frames[i].keyframe {
+            return None;
+        }
+        let mut around: Vec<usize> = video[v.saturating_sub(SCENE_CUT_WINDOW)..(v + SCENE_CUT_WINDOW + 1).min(video.len())]
+            .iter()
+            .filter(|&&j| j != i)
+            .map(|&j| frames[j].size)
+            .collect();
+        if around.is_empty() {
+            return None;
+        }
+        around.sort_unstable();
+        let median = around[around.len() / 2] as f32;
+        (frames[i].size as f32 >= median * spike).then_some(i)
+    }).collect()
+}
+
 pub fn process_frames(clean_frames: &[Frame], opt: &Opt) -> (Vec<Frame>, Vec<usize>) {
+    process_frames_with(clean_frames, opt, &ClipInfo::default())
+}
+
+pub fn process_frames_with(clean_frames: &[Frame], opt: &Opt, clip: &ClipInfo) -> (Vec<Frame>, Vec<usize>) {
     let processed_frames = match opt.mode.as_str() {
         "void" => clean_frames.to_vec(),
         "random" => {
             let mut frames = clean_frames.to_vec();
-            frames.shuffle(&mut rand::thread_rng());
+            frames.shuffle(&mut opt.rng());
             frames
         },
         "reverse" => clean_frames.iter().rev().cloned().collect(),
         "invert" => clean_frames.chunks(2).flat_map(|chunk| chunk.iter().rev()).cloned().collect(),
+        "bloom" if opt.beats.is_some() => {
+            beat_bloom(clean_frames, opt.beats.as_ref().unwrap(), opt.countframes, clip.fps)
+        },
+        "pulse" if opt.beats.is_some() => {
+            beat_pulse(clean_frames, opt.beats.as_ref().unwrap(), opt.countframes, clip.fps)
+        },
+        "bloom" if clean_frames.is_empty() => Vec::new(),
         "bloom" => {
             let repeat = opt.countframes;
-            let frame = opt.positframes;
+            let frame = opt.positframes.min(clean_frames.len() - 1);
+            if frame < opt.positframes {
+                clip.warn(format!("Bloom frame {} is past the end, blooming the last one ({})", opt.positframes, frame));
+            }
             let (lista, listb) = clean_frames.split_at(frame);
             [lista, &vec![clean_frames[frame].clone(); repeat], listb].concat()
         },
         "pulse" => {
             let pulse_len = opt.countframes;
-            let pulse_ryt = opt.positframes;
+            let pulse_ryt = opt.positframes.max(1);
             clean_frames.iter().enumerate().flat_map(|(j, frame)| {
                 if j % pulse_ryt == 0 {
                     vec![frame.clone(); pulse_len]
@@ -72,22 +557,133 @@ pub fn process_frames(clean_frames: &[Frame], opt: &Opt) -> (Vec<Frame>, Vec<usi
         },
         "jiggle" => {
             let amount = opt.positframes as f64;
-            let mut rng = rand::thread_rng();
+            let mut rng = opt.rng();
             (0..clean_frames.len()).map(|_| {
                 let index = (rng.gen::<f64>() * amount * 2.0 - amount).round() as i32;
-                let safe_index = (index as i32).rem_euclid(clean_frames.len() as i32) as usize;
+                let safe_index = index.rem_euclid(clean_frames.len() as i32) as usize;
                 clean_frames[safe_index].clone()
             }).collect()
         },
+        "markov" => {
+            let mut len = if opt.positframes > 1 { opt.positframes } else { clean_frames.len() };
+            let max_len = clean_frames.len() * MAX_MARKOV_GROWTH;
+            if len > max_len {
+                clip.warn(format!("markov walk of {} frames is more than {} times the clip, walking {}", len, MAX_MARKOV_GROWTH, max_len));
+                len = max_len;
+            }
+            markov_walk(clean_frames, opt.markov, len, opt.countframes, &mut opt.rng())
+        },
         "overlap" => {
             let pulse_len = opt.countframes;
-            let pulse_ryt = opt.positframes;
+            let pulse_ryt = opt.positframes.max(1);
             clean_frames.chunks(pulse_ryt)
                 .flat_map(|chunk| chunk.iter().take(pulse_len).cloned())
                 .collect()
         },
+        "pingpong" => {
+            // forward then back, c times, over the whole clip or windows of n frames
+            let window = if opt.positframes > 1 { opt.positframes } else { clean_frames.len().max(1) };
+            clean_frames.chunks(window).flat_map(|chunk| {
+                let back = chunk.iter().rev().skip(1).take(chunk.len().saturating_sub(2));
+                let bounce: Vec<Frame> = chunk.iter().chain(back).cloned().collect();
+                std::iter::repeat_n(bounce, opt.countframes.max(1)).flatten()
+            }).collect()
+        },
+        "stutter" => {
+            // play a window of n frames c times, then move on to the next window
+            clean_frames.chunks(opt.positframes.max(1))
+                .flat_map(|chunk| std::iter::repeat_n(chunk, opt.countframes.max(1)).flatten().cloned())
+                .collect()
+        },
+        "stride" => clean_frames.iter().step_by(opt.positframes.max(1)).cloned().collect(),
+        "sort" => {
+            let mut frames = clean_frames.to_vec();
+            frames.sort_by_key(|f| f.size);
+            frames
+        },
+        "rsort" => {
+            let mut frames = clean_frames.to_vec();
+            frames.sort_by_key(|f| std::cmp::Reverse(f.size));
+            frames
+        },
+        "blockshuffle" => {
+            let mut rng = opt.rng();
+            clean_frames.chunks(opt.positframes.max(1)).flat_map(|chunk| {
+                let mut block = chunk.to_vec();
+                block.shuffle(&mut rng);
+                block
+            }).collect()
+        },
+        "echo" => {
+            // re-insert each p-frame n frames later. with c > 1 there are more echoes
+            // at 2n, 3n.. that thin out: every 2nd frame, then every 4th and so on
+            let delay = opt.positframes.max(1);
+            let taps = opt.countframes.clamp(1, 32);
+            let mut echoes: Vec<Vec<Frame>> = vec![Vec::new(); clean_frames.len()];
+            for (i, frame) in clean_frames.iter().enumerate() {
+                if frame.frame_type != FrameType::Video || frame.keyframe {
+                    continue;
+                }
+                for k in 1..=taps {
+                    let at = i + k * delay;
+                    if at >= clean_frames.len() {
+                        break;
+                    }
+                    if i % (1 << (k - 1)) == 0 {
+                        echoes[at].push(frame.clone());
+                    }
+                }
+            }
+            clean_frames.iter().zip(echoes)
+                .flat_map(|(frame, echoes)| std::iter::once(frame.clone()).chain(echoes))
+                .collect()
+        },
+        "automosh" => {
+            // drop only the keyframes at scene cuts, then hold the first n p-frames after each cut c times
+            let cuts = detect_scene_cuts(clean_frames, SCENE_CUT_SPIKE);
+            let mut out = Vec::with_capacity(clean_frames.len());
+            let mut bloom_left = 0;
+            for (i, frame) in clean_frames.iter().enumerate() {
+                if cuts.contains(&i) {
+                    bloom_left = opt.positframes.max(1);
+                    continue;
+                }
+                if bloom_left > 0 && frame.frame_type == FrameType::Video && !frame.keyframe {
+                    bloom_left -= 1;
+                    out.extend(std::iter::repeat_n(frame.clone(), opt.countframes.max(1)));
+                } else {
+                    out.push(frame.clone());
+                }
+            }
+            out
+        },
+        "loop" => {
+            let loop_len = if opt.positframes > 1 { opt.positframes } else { 0 };
+            make_loop(clean_frames, loop_len, opt.countframes)
+        },
+        "order" => {
+            match Order::parse(opt.order.as_deref().unwrap_or("")).and_then(|order| order.eval(clean_frames.len(), &mut opt.rng())) {
+                Ok(indices) => indices
+                    .into_iter()
+                    .map(|i| clean_frames[i].clone())
+                    .collect(),
+                Err(e) => {
+                    clip.warn(format!("{}, using void", e));
+                    clean_frames.to_vec()
+                }
+            }
+        },
+        "script" => {
+            match script::run_script(opt.script.as_deref().unwrap_or(""), clean_frames, opt, clip) {
+                Ok(order) => order.into_iter().map(|i| clean_frames[i].clone()).collect(),
+                Err(e) => {
+                    clip.warn(format!("Script error: {}, using void", e));
+                    clean_frames.to_vec()
+                }
+            }
+        },
         _ => {
-            eprintln!("Mode not implemented, using void");
+            clip.warn(format!("Mode '{}' not implemented, using void", opt.mode));
             clean_frames.to_vec()
         }
     };
@@ -95,48 +691,299 @@ pub fn process_frames(clean_frames: &[Frame], opt: &Opt) -> (Vec<Frame>, Vec<usi
     (processed_frames, processed_sizes)
 }
 
-pub fn simulate_processing(mut frame_data: Vec<Frame>, steps: &[Opt]) -> Vec<Frame> {
-    let orig_frame_count = frame_data.len();
-    for step in steps {
-        let mut clean_frames = Vec::new();
-        let max_frame_size = frame_data.iter().map(|f| f.size).max().unwrap_or(0);
-        let mut prev_frame_size = 0;
-
-        // keep first video frame or not
-        if step.firstframe {
-            if let Some(first_video_frame) = frame_data.iter().find(|f| f.frame_type == FrameType::Video) {
-                clean_frames.push(first_video_frame.clone());
-                prev_frame_size = first_video_frame.size;
-            }
+/// Kill "big" frames and frames with large relative size increases, the usual
+/// way to get rid of i-frames. With `KillMode::Mutate` the keyframes (by their
+/// flag, not their size) are swapped for the previous delta frame instead, and
+/// the first `keep_keyframes` of them are left alone. Automosh is left uncleaned,
+/// it finds the keyframes to drop itself.
+pub fn clean_frames(frames: &[Frame], opt: &Opt) -> Vec<Frame> {
+    clean_frames_with(frames, opt, &ClipInfo::default())
+}
+
+pub fn clean_frames_with(frames: &[Frame], opt: &Opt, clip: &ClipInfo) -> Vec<Frame> {
+    if opt.mode == "automosh" {
+        // killing by size first would take out the cut keyframes it looks for
+        return frames.to_vec();
+    }
+    let mut clean_frames: Vec<Frame> = Vec::new();
+    let max_frame_size = frames.iter().map(|f| f.size).max().unwrap_or(0);
+    let mut prev_frame_size = 0;
+    let mut keyframes = 0;
+
+    // keep first video frame or not
+    if opt.firstframe {
+        if let Some(first_video_frame) = frames.iter().find(|f| f.frame_type == FrameType::Video) {
+            clean_frames.push(first_video_frame.clone());
+            prev_frame_size = first_video_frame.size;
         }
+    }
+
+    if opt.kill_on.is_some() && clip.audio.is_none() {
+        clip.warn("No PCM audio in the source to gate kills with, killing as usual".to_string());
+    }
 
-        // clean the list by killing "big" frames and frames with large relative size increases
-        for frame in &frame_data {
-            let keep_frame = frame.size as f32 <= (max_frame_size as f32 * step.kill) &&
-                            (frame.size as f32 <= prev_frame_size as f32 * (1.0 + step.kill_rel));
+    for frame in frames {
+        let gated = opt.kill_on.as_ref()
+            .is_some_and(|gate| clip.audio_level(gate.signal, frame).is_some_and(|level| level < gate.threshold));
 
-            if keep_frame {
+        if opt.kill_mode == KillMode::Mutate {
+            if frame.keyframe {
+                keyframes += 1;
+            }
+            if !frame.keyframe || keyframes <= opt.keep_keyframes || gated {
                 clean_frames.push(frame.clone());
+            } else {
+                // the last delta frame in its place, or keep it if there is none yet
+                let prev = clean_frames.iter().rev().find(|f| f.frame_type == frame.frame_type && !f.keyframe);
+                clean_frames.push(prev.unwrap_or(frame).clone());
             }
-            prev_frame_size = frame.size;
+            continue;
         }
 
+        let keep_frame = gated || frame.size as f32 <= (max_frame_size as f32 * opt.kill) &&
+                         (frame.size as f32 <= prev_frame_size as f32 * (1.0 + opt.kill_rel));
+        if keep_frame {
+            clean_frames.push(frame.clone());
+        }
+        prev_frame_size = frame.size;
+    }
 
-        let (processed_frames, _) = process_frames(&clean_frames, &step);
-        let mut final_frames = processed_frames;
+    clean_frames
+}
+
+/// Duplicate (or drop) frames according to `opt.multiply` and `opt.ramp`
+pub fn multiply_frames(frames: Vec<Frame>, opt: &Opt) -> Vec<Frame> {
+    multiply_frames_with(frames, opt, &ClipInfo::default())
+}
 
-        if step.multiply > 1 {
-            let mut new_frames = Vec::new();
-            for frame in final_frames {
-                for _ in 0..step.multiply {
-                    new_frames.push(frame.clone());
+pub fn multiply_frames_with(frames: Vec<Frame>, opt: &Opt, clip: &ClipInfo) -> Vec<Frame> {
+    let rates: Vec<f32> = match &opt.ramp {
+        Ramp::Flat if opt.multiply > 1 => vec![opt.multiply as f32; frames.len()],
+        Ramp::Flat => return frames,
+        Ramp::Curve(points) => {
+            let last = frames.len().saturating_sub(1).max(1) as f32;
+            (0..frames.len()).map(|i| {
+                if points.len() == 1 {
+                    return points[0];
+                }
+                let t = i as f32 / last * (points.len() - 1) as f32;
+                let k = (t.floor() as usize).min(points.len() - 2);
+                points[k] + (points[k + 1] - points[k]) * (t - k as f32)
+            }).collect()
+        },
+        Ramp::Size { min, max } => {
+            let video_sizes = frames.iter().filter(|f| f.frame_type == FrameType::Video).map(|f| f.size);
+            let smallest = video_sizes.clone().min().unwrap_or(0) as f32;
+            let biggest = video_sizes.max().unwrap_or(0) as f32;
+            frames.iter().map(|f| {
+                if f.frame_type != FrameType::Video || biggest <= smallest {
+                    return 1.0;
                 }
+                let s = (f.size as f32 - smallest) / (biggest - smallest);
+                max + (min - max) * s
+            }).collect()
+        },
+        Ramp::Audio { .. } if clip.audio.is_none() => {
+            clip.warn("No PCM audio in the source to ramp with, using multiply".to_string());
+            vec![opt.multiply.max(1) as f32; frames.len()]
+        },
+        Ramp::Audio { signal, min, max } => frames.iter().map(|f| {
+            let level = clip.audio_level(*signal, f).unwrap_or(0.0);
+            min + (max - min) * level
+        }).collect(),
+    };
+
+    // error diffusion so e.g. 1.5 alternates 1, 2, 1, 2..
+    let mut new_frames = Vec::with_capacity(rates.iter().sum::<f32>().ceil() as usize);
+    let mut carry = 0.5;
+    for (frame, rate) in frames.into_iter().zip(rates) {
+        carry += rate;
+        let count = carry.floor();
+        carry -= count;
+        new_frames.extend(std::iter::repeat_n(frame, count as usize));
+    }
+    new_frames
+}
+
+/// Hold the video frame on each beat for `hold` frames, over the frames that
+/// would have followed. The length doesn't change, so every beat stays on time.
+pub fn beat_bloom(frames: &[Frame], beats: &Beats, hold: usize, fps: f64) -> Vec<Frame> {
+    let video_count = frames.iter().filter(|f| f.frame_type == FrameType::Video).count();
+    let on_beat: std::collections::HashSet<usize> = beats.frame_numbers(fps, video_count).into_iter().collect();
+    let mut held: Option<Frame> = None;
+    let mut hold_left = 0;
+    let mut video = 0;
+    frames.iter().map(|frame| {
+        if frame.frame_type != FrameType::Video {
+            return frame.clone();
+        }
+        video += 1;
+        if on_beat.contains(&(video - 1)) {
+            held = Some(frame.clone());
+            hold_left = hold.saturating_sub(1);
+            frame.clone()
+        } else if hold_left > 0 {
+            hold_left -= 1;
+            held.clone().unwrap_or_else(|| frame.clone())
+        } else {
+            frame.clone()
+        }
+    }).collect()
+}
+
+/// Repeat the video frame that reaches each beat `repeat` times. Beats are
+/// counted on the output's timeline, so the repeats start on the beat of music
+/// laid over the result even though every pulse makes the clip longer.
+pub fn beat_pulse(frames: &[Frame], beats: &Beats, repeat: usize, fps: f64) -> Vec<Frame> {
+    let video_count = frames.iter().filter(|f| f.frame_type == FrameType::Video).count();
+    let beat_frames = beats.frame_numbers(fps, video_count * repeat.max(1));
+    let mut next_beat = beat_frames.iter().peekable();
+    let mut out = Vec::with_capacity(frames.len());
+    let mut video = 0;
+    for frame in frames {
+        if frame.frame_type != FrameType::Video {
+            out.push(frame.clone());
+            continue;
+        }
+        let copies = if next_beat.peek().is_some_and(|&&b| b <= video) { repeat.max(1) } else { 1 };
+        out.extend(std::iter::repeat_n(frame.clone(), copies));
+        video += copies;
+        while next_beat.peek().is_some_and(|&&b| b < video) {
+            next_beat.next();
+        }
+    }
+    out
+}
+
+/// Next-frame candidates and their weights for every frame, `spread` candidates each.
+/// Keyframes are never a target, landing on one would clear the mosh.
+fn markov_transitions(frames: &[Frame], model: MarkovModel, spread: usize) -> Vec<Vec<(usize, f64)>> {
+    let targets: Vec<usize> = (0..frames.len()).filter(|&i| !frames[i].keyframe).collect();
+    let mut by_size = targets.clone();
+    by_size.sort_by_key(|&i| frames[i].size);
+
+    (0..frames.len()).map(|i| match model {
+        MarkovModel::Size => {
+            // the `spread` targets closest in size to the real successor
+            let want = frames[(i + 1) % frames.len()].size;
+            let mid = by_size.partition_point(|&j| frames[j].size < want);
+            let (mut lo, mut hi) = (mid, mid);
+            while hi - lo < spread.min(by_size.len()) {
+                let take_lo = lo > 0 && (hi == by_size.len()
+                    || want - frames[by_size[lo - 1]].size <= frames[by_size[hi]].size - want);
+                if take_lo { lo -= 1 } else { hi += 1 }
             }
-            final_frames = new_frames;
+            by_size[lo..hi].iter().map(|&j| {
+                let distance = (frames[j].size as f64 - want as f64).abs() / want.max(1) as f64;
+                (j, 1.0 / (1.0 + 10.0 * distance))
+            }).collect()
+        }
+        MarkovModel::Adjacency => {
+            (-(spread as isize)..=spread as isize)
+                .filter(|&d| d != 0)
+                .filter_map(|d| {
+                    let j = i.checked_add_signed(d).filter(|&j| j < frames.len() && !frames[j].keyframe)?;
+                    Some((j, if d > 0 { 2.0 } else { 1.0 } / d.unsigned_abs() as f64))
+                })
+                .collect()
         }
-        frame_data = final_frames;
+    }).collect()
+}
+
+/// Longest markov walk, in clip lengths
+const MAX_MARKOV_GROWTH: usize = 100;
+
+/// Random walk of `len` frames over a transition model learned from `frames`,
+/// starting at the first frame. Wanders like `jiggle` but stays semi-coherent,
+/// since each step goes somewhere that plausibly follows the last.
+pub fn markov_walk<R: Rng>(frames: &[Frame], model: MarkovModel, len: usize, spread: usize, rng: &mut R) -> Vec<Frame> {
+    let transitions = markov_transitions(frames, model, spread.max(2));
+    let fallback: Vec<usize> = (0..frames.len()).filter(|&i| !frames[i].keyframe).collect();
+    if fallback.is_empty() {
+        return frames.to_vec();
     }
-    println!("> Simulated processing: {} -> {} frames using {} steps", 
+
+    let mut state = 0;
+    (0..len).map(|_| {
+        let frame = frames[state].clone();
+        state = match WeightedIndex::new(transitions[state].iter().map(|(_, w)| *w)) {
+            Ok(dist) => transitions[state][rng.sample(dist)].0,
+            // stuck between keyframes, jump anywhere
+            Err(_) => *fallback.choose(rng).unwrap(),
+        };
+        frame
+    }).collect()
+}
+
+/// A loop of the first `loop_len` frames that moshes back into itself when played
+/// on repeat. Keyframes are dropped so nothing resets at the wrap, the end
+/// ping-pongs back over the last `transition` frames, and the loop's own opening
+/// `transition` frames are moved to the end so the wrap lands mid-motion.
+/// The first play starts without a keyframe, so it takes a moment to fill in.
+pub fn make_loop(frames: &[Frame], loop_len: usize, transition: usize) -> Vec<Frame> {
+    let loop_len = if loop_len == 0 { frames.len() } else { loop_len.min(frames.len()) };
+    let body: Vec<Frame> = frames[..loop_len].iter()
+        .filter(|f| !(f.frame_type == FrameType::Video && f.keyframe))
+        .cloned()
+        .collect();
+    let transition = transition.min(body.len() / 2);
+    if transition == 0 {
+        return body;
+    }
+
+    let end = body.len();
+    let back = body[end - transition - 1..end - 1].iter().rev();
+    body[transition..].iter()
+        .chain(back)
+        .chain(body[..transition].iter())
+        .cloned()
+        .collect()
+}
+
+/// Evenly drop or duplicate frames so there are `target` video frames. Each video
+/// frame brings the audio chunks after it along, audio before the first one stays put
+pub fn retime_frames(frames: Vec<Frame>, target: usize) -> Vec<Frame> {
+    let starts: Vec<usize> = frames.iter().enumerate()
+        .filter(|(_, f)| f.frame_type == FrameType::Video)
+        .map(|(i, _)| i)
+        .collect();
+    if starts.is_empty() || target == starts.len() {
+        return frames;
+    }
+    let unit = |v: usize| &frames[starts[v]..starts.get(v + 1).copied().unwrap_or(frames.len())];
+    let mut out = frames[..starts[0]].to_vec();
+    for j in 0..target {
+        out.extend_from_slice(unit(j * starts.len() / target));
+    }
+    out
+}
+
+/// Video frame count and, if the frame rate should change, the new rate for a retime
+pub fn retime_plan(retime: &Retime, video_frames: usize, fps: f64) -> (usize, Option<f64>) {
+    match retime.target {
+        RetimeTarget::Frames(n) => (n, None),
+        RetimeTarget::Seconds(secs) if retime.rewrite_fps && secs > 0.0 && video_frames > 0 => {
+            (video_frames, Some(video_frames as f64 / secs))
+        }
+        RetimeTarget::Seconds(secs) => ((secs * fps).round().max(0.0) as usize, None),
+    }
+}
+
+/// Kill, mode and multiply for one step. With a scope only those frames go through it
+pub fn run_step(frames: &[Frame], opt: &Opt, clip: &ClipInfo) -> Vec<Frame> {
+    let scope = opt.scope.map_or(0..frames.len(), |s| s.clamp(frames.len()));
+    let clean_frames = clean_frames_with(&frames[scope.clone()], opt, clip);
+    let (processed_frames, _) = process_frames_with(&clean_frames, opt, clip);
+    let stepped = multiply_frames_with(processed_frames, opt, clip);
+    [&frames[..scope.start], &stepped[..], &frames[scope.end..]].concat()
+}
+
+pub fn simulate_processing(mut frame_data: Vec<Frame>, steps: &[Opt]) -> Vec<Frame> {
+    let orig_frame_count = frame_data.len();
+    for step in steps {
+        frame_data = run_step(&frame_data, step, &ClipInfo::default());
+    }
+    eprintln!("> Simulated processing: {} -> {} frames using {} steps", 
         orig_frame_count, frame_data.len(), steps.len());
     
     frame_data
@@ -158,6 +1005,14 @@ pub fn bstream_until_marker(input: &PathBuf, output: &PathBuf, marker: Option<&[
     Ok(startpos + mmap.len())
 }
 
+/// Positions of the movi marker and the idx1 marker after it, which everything past the headers hangs off
+fn find_markers(data: &[u8]) -> error::Result<(usize, usize)> {
+    let find = |marker: &[u8], from: usize| data[from..].windows(marker.len()).position(|w| w == marker).map(|pos| from + pos);
+    let movi = find(MOVI_MARKER, 0).ok_or(TomatwoError::MissingChunk("movi"))?;
+    let idx1 = find(IDX1_MARKER, movi).ok_or(TomatwoError::MissingChunk("idx1"))?;
+    Ok((movi, idx1))
+}
+
 pub fn build_frame_table(temp_movi: &PathBuf, include_audio: bool) -> io::Result<Vec<Frame>> {
     let file = File::open(temp_movi)?;
     let mmap = unsafe { Mmap::map(&file)? };
@@ -166,8 +1021,8 @@ pub fn build_frame_table(temp_movi: &PathBuf, include_audio: bool) -> io::Result
         .enumerate()
         .filter_map(|(i, window)| {
             match window {
-                VIDEO_FRAME_MARKER => Some(Frame { offset: i, size: 0, rel_size: 0.0, frame_type: FrameType::Video }),
-                AUDIO_FRAME_MARKER if include_audio => Some(Frame { offset: i, size: 0, rel_size: 0.0, frame_type: FrameType::Audio }),
+                VIDEO_FRAME_MARKER => Some(Frame { index: 0, offset: i, size: 0, rel_size: 0.0, frame_type: FrameType::Video, keyframe: false }),
+                AUDIO_FRAME_MARKER if include_audio => Some(Frame { index: 0, offset: i, size: 0, rel_size: 0.0, frame_type: FrameType::Audio, keyframe: false }),
                 _ => None,
             }
         })
@@ -176,6 +1031,7 @@ pub fn build_frame_table(temp_movi: &PathBuf, include_audio: bool) -> io::Result
     // Calculate frame sizes
     let mmap_len = mmap.len();
     for i in 0..frame_table.len() {
+        frame_table[i].index = i;
         frame_table[i].size = if i + 1 < frame_table.len() {
             frame_table[i + 1].offset - frame_table[i].offset
         } else {
@@ -186,10 +1042,9 @@ pub fn build_frame_table(temp_movi: &PathBuf, include_audio: bool) -> io::Result
     Ok(frame_table)
 }
 
-pub fn assemble_output_file(fileout: &PathBuf, temp_hdrl: &PathBuf, temp_movi: &PathBuf, temp_idx1: &PathBuf, final_frames: &[Frame]) -> io::Result<()> {
-    let mut output = BufWriter::new(File::create(fileout)?);
-
-    io::copy(&mut BufReader::new(File::open(temp_hdrl)?), &mut output)?;
+/// Write the AVI made of `final_frames` to `output` as it goes: the header, the frames in order, then the index
+pub fn write_output<W: Write>(output: &mut W, temp_hdrl: &PathBuf, temp_movi: &PathBuf, temp_idx1: &PathBuf, final_frames: &[Frame]) -> io::Result<()> {
+    io::copy(&mut BufReader::new(File::open(temp_hdrl)?), output)?;
 
     output.write_all(MOVI_MARKER)?;
     let movi_file = File::open(temp_movi)?;
@@ -199,103 +1054,241 @@ pub fn assemble_output_file(fileout: &PathBuf, temp_hdrl: &PathBuf, temp_movi: &
         output.write_all(&mmap[frame.offset..frame.offset + frame.size])?;
     }
 
-    io::copy(&mut BufReader::new(File::open(temp_idx1)?), &mut output)?;
-
+    io::copy(&mut BufReader::new(File::open(temp_idx1)?), output)?;
     Ok(())
 }
 
-pub fn process_video(opt: &Opt) -> io::Result<PathBuf> {
+pub fn assemble_output_file(fileout: &PathBuf, temp_hdrl: &PathBuf, temp_movi: &PathBuf, temp_idx1: &PathBuf, final_frames: &[Frame]) -> io::Result<()> {
+    let mut output = BufWriter::new(File::create(fileout)?);
+    write_output(&mut output, temp_hdrl, temp_movi, temp_idx1, final_frames)?;
+    output.flush()
+}
+
+/// Copy all of stdin into a temp file, since a pipe can't be mapped or read twice
+pub fn buffer_stdin() -> io::Result<tempfile::TempPath> {
+    let mut temp = tempfile::Builder::new().prefix("tomatwo-stdin-").suffix(".avi").tempfile()?;
+    let bytes = io::copy(&mut io::stdin().lock(), &mut temp)?;
+    if bytes == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Nothing came in on stdin"));
+    }
+    eprintln!("> Read {} bytes from stdin", bytes);
+    Ok(temp.into_temp_path())
+}
+
+/// Where `process_steps` writes, from the last step's output settings
+pub fn output_target(steps: &[Opt]) -> io::Result<output::OutputTarget> {
+    let (opt, last) = match (steps.first(), steps.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Ok(output::OutputTarget::Write(PathBuf::new())),
+    };
+    if let Some(path) = &last.output {
+        return output::pick(last.overwrite, |n| output::numbered(path, n));
+    }
+    let step_names: Vec<String> = steps.iter().map(|step| {
+        let cname = if step.countframes > 1 { format!("-c{}", step.countframes) } else { String::new() };
+        let pname = if step.positframes > 1 { format!("-n{}", step.positframes) } else { String::new() };
+        format!("{}{}{}", step.mode, cname, pname)
+    }).collect();
+    let parts = output::NameParts {
+        stem: opt.input.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+        mode: steps.iter().map(|s| s.mode.as_str()).collect::<Vec<_>>().join("-"),
+        params: step_names.join("-"),
+        seed: steps.iter().find_map(|s| s.seed),
+        date: output::date_stamp(),
+    };
+    let dir = last.output_dir.clone()
+        .unwrap_or_else(|| opt.input.parent().map(PathBuf::from).unwrap_or_default());
+    let template = last.name_template.as_deref().unwrap_or(output::DEFAULT_TEMPLATE);
+    output::pick(last.overwrite, |n| dir.join(output::file_name(template, &parts, n)))
+}
+
+/// One step of a `ProcessReport`
+#[derive(Clone, Debug, Serialize)]
+pub struct StepReport {
+    pub mode: String,
+    /// video frames going in and coming out
+    pub frames_in: usize,
+    pub frames_out: usize,
+    pub seed: Option<u64>,
+    pub millis: f64,
+}
+
+/// What `process_steps_report` did
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ProcessReport {
+    pub input: PathBuf,
+    /// the written file, `-` for stdout, empty for a preview
+    pub output: PathBuf,
+    /// the output already existed and `Overwrite::Skip` left it alone
+    pub skipped: bool,
+    /// video frames in the source
+    pub input_frames: usize,
+    /// video frames written
+    pub output_frames: usize,
+    /// source video frames that don't appear in the output at all
+    pub dropped_frames: usize,
+    pub steps: Vec<StepReport>,
+    pub warnings: Vec<String>,
+    pub millis: f64,
+}
+
+fn video_count(frames: &[Frame]) -> usize {
+    frames.iter().filter(|f| f.frame_type == FrameType::Video).count()
+}
+
+fn millis(duration: std::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+pub fn process_video(opt: &Opt) -> error::Result<PathBuf> {
+    process_steps(std::slice::from_ref(opt))
+}
+
+/// Run `steps` one after another in memory. The input, audio and EDL import come
+/// from the first step, retime, EDL export, preview and output from the last.
+pub fn process_steps(steps: &[Opt]) -> error::Result<PathBuf> {
+    process_steps_report(steps).map(|report| report.output)
+}
+
+/// `process_steps`, returning what happened. Steps without a seed get a random
+/// one, so the report says how to get the same result again
+pub fn process_steps_report(steps: &[Opt]) -> error::Result<ProcessReport> {
+    for step in steps {
+        step.validate()?;
+    }
+    let steps: Vec<Opt> = steps.iter()
+        .map(|step| Opt { seed: Some(step.seed.unwrap_or_else(rand::random)), ..step.clone() })
+        .collect();
+    let steps = &steps[..];
+    let (opt, last) = match (steps.first(), steps.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(TomatwoError::bad_param("steps", "there are none")),
+    };
+    let to_stdout = last.output.as_deref().is_some_and(output::is_stdout);
+    let fileout = if last.preview || to_stdout {
+        PathBuf::new()
+    } else {
+        match output_target(steps)? {
+            output::OutputTarget::Write(path) => path,
+            output::OutputTarget::Exists(path) => {
+                eprintln!("> Skipped, output already exists: {:?}", path);
+                return Ok(ProcessReport { input: opt.input.clone(), output: path, skipped: true, ..Default::default() });
+            }
+        }
+    };
+    let claim = output::Claim::new((last.overwrite == Overwrite::Increment && !fileout.as_os_str().is_empty()).then(|| fileout.clone()));
     let timer = std::time::Instant::now();
+    find_markers(unsafe { &Mmap::map(&File::open(&opt.input)?)? })?;
     let temp_dir = tempfile::tempdir()?;
     let temp_hdrl = temp_dir.path().join("hdrl.bin");
     let temp_movi = temp_dir.path().join("movi.bin");
     let temp_idx1 = temp_dir.path().join("idx1.bin");
 
-    let movi_marker_pos = bstream_until_marker(&opt.input, &temp_hdrl, Some(b"movi"), 0)?;
-    let idx1_marker_pos = bstream_until_marker(&opt.input, &temp_movi, Some(b"idx1"), movi_marker_pos)?;
+    let movi_marker_pos = bstream_until_marker(&opt.input, &temp_hdrl, Some(MOVI_MARKER), 0)?;
+    let idx1_marker_pos = bstream_until_marker(&opt.input, &temp_movi, Some(IDX1_MARKER), movi_marker_pos)?;
     bstream_until_marker(&opt.input, &temp_idx1, None, idx1_marker_pos)?;
 
-    let frame_table = build_frame_table(&temp_movi, opt.audio)?;
-
-    let mut clean_frames = Vec::new();
-    let max_frame_size = frame_table.iter().map(|f| f.size).max().unwrap_or(0);
-    let mut prev_frame_size = 0;
-
-    // keep first video frame or not
-    if opt.firstframe {
-        if let Some(first_video_frame) = frame_table.iter().find(|f| f.frame_type == FrameType::Video) {
-            clean_frames.push(first_video_frame.clone());
-            prev_frame_size = first_video_frame.size;
+    let mut frame_table = build_frame_table(&temp_movi, opt.audio)?;
+    let layout = read_layout(&opt.input).ok();
+    let clip = match &layout {
+        Some(layout) => {
+            mark_keyframes(&mut frame_table, layout);
+            let mut clip = ClipInfo::from_layout(layout);
+            if opt.edl_in.is_none() && steps.iter().any(Opt::uses_audio) {
+                clip.audio = audio::read_envelope(&opt.input)?.map(|a| a.for_frame_table(&frame_table));
+            }
+            clip
         }
-    }
+        None => ClipInfo::default(),
+    };
 
-    // clean the list by killing "big" frames and frames with large relative size increases
-    for frame in &frame_table {
-        let keep_frame = frame.size as f32 <= (max_frame_size as f32 * opt.kill) &&
-                         (frame.size as f32 <= prev_frame_size as f32 * (1.0 + opt.kill_rel));
+    let input_frames = video_count(&frame_table);
+    let mut step_reports = Vec::new();
+    let mut final_frames = if let Some(edl_in) = &opt.edl_in {
+        edl::apply_edl(&edl::read_edl(edl_in)?, &frame_table, &clip)?
+    } else {
+        steps.iter().fold(frame_table, |frames, step| {
+            let step_timer = std::time::Instant::now();
+            let out = run_step(&frames, step, &clip);
+            step_reports.push(StepReport {
+                mode: step.mode.clone(),
+                frames_in: video_count(&frames),
+                frames_out: video_count(&out),
+                seed: step.seed,
+                millis: millis(step_timer.elapsed()),
+            });
+            out
+        })
+    };
 
-        if keep_frame {
-            clean_frames.push(frame.clone());
+    if let Some(retime) = &last.retime {
+        let video_frames = final_frames.iter().filter(|f| f.frame_type == FrameType::Video).count();
+        let (target, new_fps) = retime_plan(retime, video_frames, clip.fps);
+        final_frames = retime_frames(final_frames, target);
+        match (new_fps, &layout) {
+            (Some(fps), Some(layout)) => {
+                let mut hdrl = std::fs::read(&temp_hdrl)?;
+                avi::set_frame_rate(&mut hdrl, layout, fps);
+                std::fs::write(&temp_hdrl, hdrl)?;
+                eprintln!("> Retimed: frame rate {:.3} -> {:.3}", clip.fps, fps);
+            }
+            (Some(_), None) => clip.warn("Couldn't read the AVI headers, frame rate left alone".to_string()),
+            (None, _) => eprintln!("> Retimed: {} -> {} video frames", video_frames, target),
         }
-        prev_frame_size = frame.size;
     }
 
-
-    let (processed_frames, _) = process_frames(&clean_frames, &opt);
-    let mut final_frames = processed_frames;
-
-    if opt.multiply > 1 {
-        let mut new_frames = Vec::new();
-        for frame in final_frames {
-            for _ in 0..opt.multiply {
-                new_frames.push(frame.clone());
-            }
-        }
-        final_frames = new_frames;
+    if let Some(edl_out) = &last.edl_out {
+        edl::write_edl(edl_out, &final_frames)?;
+        eprintln!("> Frame order written to {:?}", edl_out);
     }
 
-    if opt.preview {
+    let used: std::collections::HashSet<usize> = final_frames.iter()
+        .filter(|f| f.frame_type == FrameType::Video)
+        .map(|f| f.index)
+        .collect();
+    let mut report = ProcessReport {
+        input: opt.input.clone(),
+        output: PathBuf::new(),
+        skipped: false,
+        input_frames,
+        output_frames: video_count(&final_frames),
+        dropped_frames: input_frames.saturating_sub(used.len()),
+        steps: step_reports,
+        warnings: clip.warnings.take(),
+        millis: 0.0,
+    };
+
+    if last.preview {
         preview_output(&temp_hdrl, &temp_movi, &temp_idx1, &final_frames)?;
-        Ok(PathBuf::new())
+    } else if to_stdout {
+        eprintln!("> Processing complete, streaming to stdout... {:.2?}", timer.elapsed());
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        write_output(&mut stdout, &temp_hdrl, &temp_movi, &temp_idx1, &final_frames)?;
+        stdout.flush()?;
+        eprintln!("> Total time: {:.2?}", timer.elapsed());
+        report.output = PathBuf::from("-");
     } else {
-        println!("> Processing complete, writing output file... {:.2?}", timer.elapsed());
-    
-        let cname = if opt.countframes > 1 { format!("-c{}", opt.countframes) } else { String::new() };
-        let pname = if opt.positframes > 1 { format!("-n{}", opt.positframes) } else { String::new() };
-        let fileout = opt.input.with_file_name(format!("{}-{}{}{}.avi", 
-            opt.input.file_stem().unwrap().to_str().unwrap(), 
-            opt.mode, cname, pname));
-        assemble_output_file(&fileout, &temp_hdrl, &temp_movi, &temp_idx1, &final_frames)?;
-        println!("> Done! Output file: {:?}", fileout);
-        println!("> Total time: {:.2?}", timer.elapsed());
-        Ok(fileout)
-    } 
-}
-
-pub fn preview_output(temp_hdrl: &PathBuf, temp_movi: &PathBuf, temp_idx1: &PathBuf, final_frames: &[Frame]) -> io::Result<()> {
+        eprintln!("> Processing complete, writing output file... {:.2?}", timer.elapsed());
+        output::write_atomic(&fileout, |path| assemble_output_file(&path.to_path_buf(), &temp_hdrl, &temp_movi, &temp_idx1, &final_frames))?;
+        claim.keep();
+        eprintln!("> Done! Output file: {:?}", fileout);
+        eprintln!("> Total time: {:.2?}", timer.elapsed());
+        report.output = fileout;
+    }
+    report.millis = millis(timer.elapsed());
+    Ok(report)
+}
+
+pub fn preview_output(temp_hdrl: &PathBuf, temp_movi: &PathBuf, temp_idx1: &PathBuf, final_frames: &[Frame]) -> error::Result<()> {
     let mut ffplay = Command::new("ffplay")
-        .args(&["-f", "avi", "-i", "-"])  // Read from stdin
+        .args(["-f", "avi", "-i", "-"])  // Read from stdin
         .stdin(Stdio::piped())
-        .spawn()?;
-
-    let mut ffplay_stdin = ffplay.stdin.take().expect("Failed to open ffplay stdin");
+        .spawn()
+        .map_err(|e| TomatwoError::Ffmpeg(format!("Couldn't run ffplay, is it installed and on PATH? ({})", e)))?;
 
-    // Write HDRL
-    io::copy(&mut File::open(temp_hdrl)?, &mut ffplay_stdin)?;
-
-    // Write MOVI header
-    ffplay_stdin.write_all(b"movi")?;
-
-    // Write frames
-    let movi_file = File::open(temp_movi)?;
-    let mmap = unsafe { Mmap::map(&movi_file)? };
-
-    for frame in final_frames {
-        ffplay_stdin.write_all(&mmap[frame.offset..frame.offset + frame.size])?;
-    }
-
-    // Write IDX1
-    io::copy(&mut File::open(temp_idx1)?, &mut ffplay_stdin)?;
+    let mut ffplay_stdin = ffplay.stdin.take()
+        .ok_or_else(|| TomatwoError::Ffmpeg("Failed to open ffplay stdin".to_string()))?;
+    write_output(&mut ffplay_stdin, temp_hdrl, temp_movi, temp_idx1, final_frames)?;
 
     // Close stdin to signal end of input
     drop(ffplay_stdin);
@@ -306,46 +1299,132 @@ pub fn preview_output(temp_hdrl: &PathBuf, temp_movi: &PathBuf, temp_idx1: &Path
     Ok(())
 }
 
-pub fn extract_frame_data(avi_path: &PathBuf) -> io::Result<(Vec<Frame>, usize)> {
+pub fn extract_frame_data(avi_path: &PathBuf) -> error::Result<(Vec<Frame>, usize)> {
     let file = File::open(avi_path)?;
     let mmap = unsafe { Mmap::map(&file)? };
+    let (movi_start, idx1_start) = find_markers(&mmap)?;
+    let movi_data = &mmap[movi_start..idx1_start];
 
-    let movi_marker = b"movi";
-    let idx1_marker = b"idx1";
+    let frame_table: Vec<Frame> = movi_data.par_windows(4)
+        .enumerate()
+        .filter_map(|(i, window)| {
+            if window == VIDEO_FRAME_MARKER {
+                Some(Frame { index: 0, offset: movi_start + i, size: 0, rel_size: 0.0, frame_type: FrameType::Video, keyframe: false })
+            } else {
+                None
+            }
+        })
+        .collect();
 
-    if let (Some(movi_start), Some(idx1_start)) = (
-        mmap.windows(movi_marker.len()).position(|window| window == movi_marker),
-        mmap.windows(idx1_marker.len()).position(|window| window == idx1_marker)
-    ) {
-        let movi_data = &mmap[movi_start..idx1_start];
+    let mut last_frame_size = 0;
+    let mut frame_table = frame_table;
+    for i in 0..frame_table.len() {
+        frame_table[i].index = i;
+        if i + 1 < frame_table.len() {
+            frame_table[i].size = (frame_table[i + 1].offset - frame_table[i].offset).saturating_sub(8); // Subtract 8 for chunk header
+            frame_table[i].rel_size = frame_table[i].size as f32 / last_frame_size as f32;
+            last_frame_size = frame_table[i].size;
+        } else {
+            frame_table[i].size = (idx1_start - frame_table[i].offset).saturating_sub(8); // Use idx1_start as the end of movi data
+        };
+    }
 
-        let frame_table: Vec<Frame> = movi_data.par_windows(4)
-            .enumerate()
-            .filter_map(|(i, window)| {
-                if window == b"00dc" {
-                    Some(Frame { offset: movi_start + i, size: 0, rel_size: 0.0, frame_type: FrameType::Video })
-                } else {
-                    None
-                }
-            })
-            .collect();
+    if let Ok(layout) = avi::parse_avi(&mmap) {
+        mark_keyframes(&mut frame_table, &layout);
+    }
+    let max_frame_size = frame_table.iter().map(|f| f.size).max().unwrap_or(0);
 
-        let mut last_frame_size = 0;
-        let mut frame_table = frame_table;
-        for i in 0..frame_table.len() {
-            if i + 1 < frame_table.len() {
-                frame_table[i].size = frame_table[i + 1].offset - frame_table[i].offset - 8; // Subtract 8 for chunk header
-                frame_table[i].rel_size = frame_table[i].size as f32 / last_frame_size as f32;
-                last_frame_size = frame_table[i].size;
-            } else {
-                frame_table[i].size = idx1_start - frame_table[i].offset - 8; // Use idx1_start as the end of movi data
-            };
+    Ok((frame_table, max_frame_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(index: usize, size: usize, keyframe: bool) -> Frame {
+        Frame { index, offset: index * 100, size, rel_size: 0.0, frame_type: FrameType::Video, keyframe }
+    }
+
+    #[test]
+    fn mutate_swaps_flagged_keyframes_only() {
+        // a big delta frame at 2 that the size heuristic would kill, keyframes at 0 and 3
+        let frames = vec![video(0, 900, true), video(1, 10, false), video(2, 800, false), video(3, 50, true), video(4, 10, false)];
+        let opt = Opt { kill_mode: KillMode::Mutate, keep_keyframes: 1, ..Default::default() };
+        let indices: Vec<usize> = clean_frames(&frames, &opt).iter().map(|f| f.index).collect();
+        assert_eq!(indices, vec![0, 1, 2, 2, 4]);
+
+        let opt = Opt { keep_keyframes: 0, ..opt };
+        let indices: Vec<usize> = clean_frames(&frames, &opt).iter().map(|f| f.index).collect();
+        assert_eq!(indices, vec![0, 1, 2, 2, 4], "a keyframe with no delta before it stays");
+    }
+
+    #[test]
+    fn automosh_holds_the_frames_after_a_cut() {
+        let frames: Vec<Frame> = (0..20).map(|i| match i {
+            0 | 10 => video(i, 1000, true),
+            _ => video(i, 100, false),
+        }).collect();
+        let opt = Opt { mode: "automosh".to_string(), positframes: 2, countframes: 3, ..Default::default() };
+        let indices: Vec<usize> = run_step(&frames, &opt, &ClipInfo::default()).iter().map(|f| f.index).collect();
+        let expected: Vec<usize> = (0..10).chain([11, 11, 11, 12, 12, 12]).chain(13..20).collect();
+        assert_eq!(indices, expected);
+
+        let opt = Opt { kill: 1.0, kill_rel: 100.0, ..opt };
+        let indices: Vec<usize> = run_step(&frames, &opt, &ClipInfo::default()).iter().map(|f| f.index).collect();
+        assert_eq!(indices, expected, "the kill settings don't matter");
+    }
+
+    fn audio(index: usize) -> Frame {
+        Frame { frame_type: FrameType::Audio, ..video(index, 10, false) }
+    }
+
+    #[test]
+    fn retime_video_only() {
+        let frames: Vec<Frame> = (0..10).map(|i| video(i, 10, false)).collect();
+        let indices = |target| retime_frames(frames.clone(), target).iter().map(|f| f.index).collect::<Vec<_>>();
+        assert_eq!(indices(5), vec![0, 2, 4, 6, 8]);
+        assert_eq!(indices(15), vec![0, 0, 1, 2, 2, 3, 4, 4, 5, 6, 6, 7, 8, 8, 9]);
+        assert_eq!(indices(10), (0..10).collect::<Vec<_>>());
+        assert!(indices(0).is_empty());
+    }
+
+    #[test]
+    fn retime_carries_audio_with_its_video_frame() {
+        // audio first, then each video frame followed by one audio chunk
+        let mut frames = vec![audio(0)];
+        for v in 0..10 {
+            frames.push(video(1 + v * 2, 10, false));
+            frames.push(audio(2 + v * 2));
+        }
+        for target in [7, 15, 20] {
+            let out = retime_frames(frames.clone(), target);
+            assert_eq!(out.iter().filter(|f| f.frame_type == FrameType::Video).count(), target);
+            assert_eq!(out[0].index, 0);
+            for pair in out[1..].chunks(2) {
+                assert_eq!((&pair[0].frame_type, &pair[1].frame_type), (&FrameType::Video, &FrameType::Audio));
+                assert_eq!(pair[1].index, pair[0].index + 1);
+            }
         }
+    }
 
-        let max_frame_size = frame_table.iter().map(|f| f.size).max().unwrap_or(0);
+    #[test]
+    fn markov_walk_length_is_capped() {
+        let frames: Vec<Frame> = (0..5).map(|i| video(i, 10 + i, false)).collect();
+        let clip = ClipInfo::default();
+        let opt = Opt { mode: "markov".to_string(), positframes: 1_000_000_000, seed: Some(1), ..Default::default() };
+        let (out, _) = process_frames_with(&frames, &opt, &clip);
+        assert_eq!(out.len(), 500);
+        assert_eq!(clip.warnings.borrow().len(), 1);
 
-        Ok((frame_table, max_frame_size))
-    } else {
-        Err(io::Error::new(io::ErrorKind::InvalidData, "Could not find 'movi' or 'idx1' marker in AVI file"))
+        let opt = Opt { positframes: 12, ..opt };
+        assert_eq!(process_frames_with(&frames, &opt, &clip).0.len(), 12);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn drop_ignores_keep_keyframes() {
+        let frames = vec![video(0, 900, true), video(1, 10, false), video(2, 11, false)];
+        let opt = Opt { keep_keyframes: 5, ..Default::default() };
+        let indices: Vec<usize> = clean_frames(&frames, &opt).iter().map(|f| f.index).collect();
+        assert_eq!(indices, vec![1, 2]);
+    }
+}